@@ -0,0 +1,1278 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant, Interval, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::codec::{Codec, DecodeError};
+use crate::config::{AddrFamily, Config};
+use crate::error::CommError;
+use crate::message::{FinStats, Message, MsgKind, Peer, seq_add, seq_diff};
+use crate::metrics::Metrics;
+use crate::progress::Progress;
+use crate::recv_log::RecvLog;
+use crate::server::SessionStat;
+use crate::sim::NetSim;
+use crate::{
+    DEFAULT_CHUNK_SIZE, HEX_DUMP_LEVEL, PACKET, RECV_BUF_SIZE, apply_dscp, log_hex_dump,
+    log_throughput, warn_if_truncated,
+};
+
+/// FIN-ACK が来ないときに FIN を再送する回数
+const FIN_RETRIES: u32 = 3;
+
+/// Pong が返ってこないまま Ping をこの回数送ったら、サーバが落ちている可能性を警告する
+const KEEPALIVE_MISSES: u32 = 3;
+
+/// ConnectionRefused がこの回数続いたら、サーバが動いていないとみなして諦める
+const MAX_REFUSED: u32 = 3;
+
+/// 指数バックオフの上限
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// retry 回目の応答待ち時間を base * 2^retry で求める (max で頭打ち)
+fn backoff(retry: u32, base: Duration, max: Duration) -> Duration {
+    match 1u32.checked_shl(retry) {
+        Some(factor) => base.saturating_mul(factor).min(max),
+        None => max,
+    }
+}
+
+/// サーバとの接続。送信は必ず sim を通す
+struct Conn<'a> {
+    socket: &'a UdpSocket,
+    /// ログの接頭辞と、送信するメッセージの label
+    label: &'a str,
+    codec: Codec,
+    sim: NetSim,
+    /// この実行のセッション ID。送信するすべてのメッセージに付ける
+    session: u64,
+    metrics: Metrics,
+    /// 受信したデータグラムをパース前に 16 進ダンプで出す
+    hex_dump: bool,
+    /// --pps を指定したときの送信間隔。リンクに流すパケット数の上限なので、
+    /// Data だけでなく再送や SYN / FIN / Ping / Ack もすべてこの間隔で送る
+    pacer: Option<Interval>,
+    /// 続けて ConnectionRefused になった回数 (何か受信できたら 0 に戻す)
+    refused: u32,
+}
+
+impl Conn<'_> {
+    /// msg をエンコードして送信し、送信したバイト数を metrics に数える
+    async fn send(&mut self, msg: &Message) -> Result<(), CommError> {
+        let data = self.codec.encode(msg)?;
+        self.send_encoded(&data, msg.no).await
+    }
+
+    /// エンコード済みの no のデータグラムを送信する
+    ///
+    /// 一時的な送信エラーはパケットが失われたのと同じ扱いにして、タイムアウト後の再送 (retry+1) に任せる
+    async fn send_encoded(&mut self, data: &[u8], no: u32) -> Result<(), CommError> {
+        self.pace().await;
+        self.send_paced(data, no).await
+    }
+
+    /// --pps の次の送信時刻まで待つ (指定がなければすぐ返る)
+    async fn pace(&mut self) {
+        if let Some(pacer) = &mut self.pacer {
+            pacer.tick().await;
+        }
+    }
+
+    /// pace を待たずに送る send_encoded (RTT に --pps の待ち時間を含めないように、先に pace を待つとき用)
+    async fn send_paced(&mut self, data: &[u8], no: u32) -> Result<(), CommError> {
+        match self.sim.send(self.socket, data, self.label, no).await {
+            Ok(()) => {
+                self.metrics.on_send(data.len());
+                Ok(())
+            }
+            Err(e) if is_transient(&e) => {
+                warn!(
+                    no,
+                    "[{}] 一時的な送信エラー (no={}): {}。再送に任せます", self.label, no, e
+                );
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => self.on_refused(),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// n バイト受信できたので、受信数を数えて ConnectionRefused の連続をリセットする
+    fn on_recv(&mut self, n: usize) {
+        self.metrics.on_recv(n);
+        self.refused = 0;
+    }
+
+    /// recv のエラーをログに出す。ConnectionRefused が MAX_REFUSED 回続いたらエラーを返す
+    fn on_recv_error(&mut self, e: &io::Error) -> Result<(), CommError> {
+        if e.kind() == io::ErrorKind::ConnectionRefused {
+            return self.on_refused();
+        }
+        warn!("[{}] recv エラー: {}", self.label, e);
+        Ok(())
+    }
+
+    /// connect した UDP ソケットでは、ICMP port unreachable が次の send / recv の ConnectionRefused になる
+    fn on_refused(&mut self) -> Result<(), CommError> {
+        self.refused += 1;
+        warn!(
+            refused = self.refused,
+            "[{}] server not reachable (connection refused)", self.label
+        );
+        if self.refused >= MAX_REFUSED {
+            error!(
+                "[{}] {} 回続けて connection refused になりました。サーバが動いていないようです",
+                self.label, self.refused
+            );
+            return Err(CommError::Unreachable {
+                refused: self.refused,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 1 秒に pps 個までしか送らないように、送信の前に待つ間隔を作る (最初の 1 個はすぐ送る)
+fn pacer(pps: u32) -> Interval {
+    let mut interval = time::interval(Duration::from_secs(1) / pps);
+    // 遅れを取り戻そうとまとめて送らないようにする
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// 再送すれば成功する見込みのある送信エラーか
+///
+/// EMSGSIZE のように同じデータを送り直しても失敗し続けるものは含めない
+/// (再送回数が無制限だと終わらなくなるため)
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut
+    )
+}
+
+/// SYN を送り、サーバから SYN が返ってくるまで待つ
+async fn handshake(
+    conn: &mut Conn<'_>,
+    timeout: Duration,
+    max_retries: u32,
+    start_no: u32,
+) -> Result<(), CommError> {
+    let mut retry: u32 = 0;
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+
+    loop {
+        if retry > max_retries {
+            error!(
+                retries = max_retries,
+                "[{}] SYN gave up after {} retries", conn.label, max_retries
+            );
+            // SYN だけ届いて応答が失われた場合に備え、サーバ側のセッションも捨てさせる
+            send_rst(conn).await?;
+            return Err(CommError::GaveUp {
+                what: "SYN".to_string(),
+                retries: max_retries,
+            });
+        }
+
+        // SYN の no で、これから送る最初の no をサーバに知らせる
+        let syn = Message::syn(start_no, Peer::Client)
+            .with_retry(retry)
+            .with_label(conn.label)
+            .with_session(conn.session);
+        info!("[{}] SYN 送信: {:?}", conn.label, syn);
+        conn.send(&syn).await?;
+
+        let wait = backoff(retry, timeout, MAX_BACKOFF.max(timeout));
+        match time::timeout(wait, conn.socket.recv(&mut buf)).await {
+            Ok(Ok(n)) => {
+                conn.on_recv(n);
+                if conn.hex_dump {
+                    log_hex_dump(conn.label, &buf[..n]);
+                }
+                warn_if_truncated(conn.label, n, buf.len());
+                match conn.codec.decode(&buf[..n]) {
+                    Ok(reply)
+                        if matches!(reply.kind, MsgKind::Syn)
+                            && matches!(reply.from, Peer::Server) =>
+                    {
+                        info!("[{}] SYN 受信: {:?}", conn.label, reply);
+                        return Ok(());
+                    }
+                    Ok(reply) => {
+                        warn!("[{}] SYN 待ちに想定外のメッセージ: {:?}", conn.label, reply);
+                        retry += 1;
+                    }
+                    Err(DecodeError::CrcMismatch) => {
+                        warn!("[{}] CRC mismatch, discarding", conn.label);
+                        retry += 1;
+                    }
+                    Err(e) => {
+                        warn!("[{}] {}", conn.label, e);
+                        retry += 1;
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                conn.on_recv_error(&e)?;
+                retry += 1;
+            }
+            Err(_) => {
+                conn.metrics.timeouts += 1;
+                retry += 1;
+                info!(
+                    retry,
+                    "[{}] SYN タイムアウト: retry={} で再送します", conn.label, retry
+                );
+            }
+        }
+    }
+}
+
+/// サーバのホスト名または IP (IPv4 / IPv6) とポートから接続先のアドレスを作る
+///
+/// 名前解決の結果が複数あれば、family に合う最初のアドレスを使う (None なら先頭)
+pub async fn server_addr(
+    host: &str,
+    port: u16,
+    family: Option<AddrFamily>,
+) -> Result<SocketAddr, CommError> {
+    let resolve_err = |source| CommError::Resolve {
+        host: host.to_string(),
+        source,
+    };
+    let addr = lookup_host((host, port))
+        .await
+        .map_err(resolve_err)?
+        .find(|addr| family.is_none_or(|f| f.matches(addr)))
+        .ok_or_else(|| {
+            resolve_err(io::Error::other(
+                "指定したアドレスファミリのアドレスがありません",
+            ))
+        })?;
+    Ok(addr)
+}
+
+/// FIN を送り、サーバからの FIN-ACK を待つ。届かなければ FIN_RETRIES 回まで再送する
+///
+/// FIN には stats を載せ、サーバが自分の受信ログと突き合わせられるようにする
+async fn close(conn: &mut Conn<'_>, timeout: Duration, stats: FinStats) -> Result<(), CommError> {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    for retry in 0..=FIN_RETRIES {
+        // FIN を送信（no は Data と重ならない FIN_NO）
+        let fin = Message::fin(Peer::Client)
+            .with_retry(retry)
+            .with_label(conn.label)
+            .with_session(conn.session)
+            .with_stats(stats);
+        info!("[{}] FIN 送信: {:?}", conn.label, fin);
+        conn.send(&fin).await?;
+
+        let wait = backoff(retry, timeout, MAX_BACKOFF.max(timeout));
+        match time::timeout(wait, conn.socket.recv(&mut buf)).await {
+            Ok(Ok(n)) => {
+                conn.on_recv(n);
+                if conn.hex_dump {
+                    log_hex_dump(conn.label, &buf[..n]);
+                }
+                warn_if_truncated(conn.label, n, buf.len());
+                match conn.codec.decode(&buf[..n]) {
+                    Ok(reply)
+                        if matches!(reply.kind, MsgKind::Fin)
+                            && matches!(reply.from, Peer::Server) =>
+                    {
+                        info!("[{}] FIN-ACK 受信: {:?}", conn.label, reply);
+                        return Ok(());
+                    }
+                    Ok(reply) => {
+                        // 遅れて届いた Data の再送などは読み捨てる
+                        warn!(
+                            "[{}] FIN-ACK 待ちに想定外のメッセージ: {:?}",
+                            conn.label, reply
+                        );
+                    }
+                    Err(DecodeError::CrcMismatch) => {
+                        warn!("[{}] CRC mismatch, discarding", conn.label);
+                    }
+                    Err(e) => {
+                        warn!("[{}] {}", conn.label, e);
+                    }
+                }
+            }
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                // FIN を受信したサーバが先に終了している (--once で FIN-ACK が失われたなど) ので、待っても返ってこない
+                warn!(
+                    "[{}] server not reachable (connection refused)。FIN-ACK を待たずに終了します",
+                    conn.label
+                );
+                return Ok(());
+            }
+            Ok(Err(e)) => {
+                conn.on_recv_error(&e)?;
+            }
+            Err(_) => {
+                conn.metrics.timeouts += 1;
+                info!(
+                    retry,
+                    "[{}] FIN-ACK タイムアウト: retry={}", conn.label, retry
+                );
+            }
+        }
+    }
+
+    warn!(
+        "[{}] FIN-ACK を受信できませんでした ({} 回再送)",
+        conn.label, FIN_RETRIES
+    );
+    Ok(())
+}
+
+/// FIN に載せるまとめ。sent は新規に送信した no の数、acked は応答済みの no の数
+/// (応答が落ちても累積 ACK で届いたとわかった分を含むので、受信ログの数とは限らない)
+fn fin_stats(sent: u32, acked: u32) -> FinStats {
+    FinStats { sent, acked }
+}
+
+/// no ごとに中身が変わる payload_size バイトのデータを作る (応答の照合用)
+pub(crate) fn build_payload(no: u32, payload_size: usize) -> Vec<u8> {
+    (0..payload_size)
+        .map(|i| (no as usize).wrapping_add(i) as u8)
+        .collect()
+}
+
+/// Data に載せる payload の作り方
+enum Payload {
+    /// build_payload で作るパターン (指定バイト数)
+    Pattern(usize),
+    /// ファイルを分割したチャンク。no = first から順に 1 つずつ載せる
+    Chunks { first: u32, chunks: Vec<Vec<u8>> },
+}
+
+impl Payload {
+    /// path のファイルを chunk_size バイトずつに分ける (最後のチャンクは短くてもよい)
+    fn from_file(path: &Path, chunk_size: usize, first: u32) -> Result<Self, CommError> {
+        let data = fs::read(path)?;
+        let mut chunks: Vec<Vec<u8>> = data.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+        if chunks.is_empty() {
+            // 空のファイルでも、空の payload を 1 つ送って終わる
+            chunks.push(Vec::new());
+        }
+        Ok(Payload::Chunks { first, chunks })
+    }
+
+    /// 送るメッセージ数 (パターンなら count のまま)
+    fn count(&self, count: u32) -> Result<u32, CommError> {
+        match self {
+            Payload::Pattern(_) => Ok(count),
+            Payload::Chunks { chunks, .. } => u32::try_from(chunks.len())
+                .map_err(|_| CommError::BadArgs("ファイルのチャンク数が多すぎます".to_string())),
+        }
+    }
+
+    /// no に載せる payload。チャンクは first から seq_next の順に数える (u32::MAX の次は 1)
+    fn for_no(&self, no: u32) -> Result<Vec<u8>, CommError> {
+        match self {
+            Payload::Pattern(size) => Ok(build_payload(no, *size)),
+            Payload::Chunks { first, chunks } => chunks
+                .get(seq_diff(*first, no) as usize)
+                .cloned()
+                .ok_or(CommError::NoChunk { no }),
+        }
+    }
+}
+
+/// RTT の集計 (最小・最大・平均・標準偏差)
+///
+/// 標準偏差は Welford 法で求めるので、サンプルを溜めずに済む
+#[derive(Default)]
+struct RttStats {
+    min: Option<Duration>,
+    max: Option<Duration>,
+    sum: Duration,
+    count: u32,
+    /// これまでの RTT の平均 (ミリ秒)
+    mean_ms: f64,
+    /// 平均からの差の 2 乗和 (ミリ秒^2)
+    m2: f64,
+}
+
+impl RttStats {
+    fn add(&mut self, rtt: Duration) {
+        self.min = Some(self.min.map_or(rtt, |m| m.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |m| m.max(rtt)));
+        self.sum += rtt;
+        self.count += 1;
+
+        let ms = as_ms(rtt);
+        let delta = ms - self.mean_ms;
+        self.mean_ms += delta / f64::from(self.count);
+        self.m2 += delta * (ms - self.mean_ms);
+    }
+
+    /// RTT の標準偏差 (ミリ秒)。計測がなければ None
+    fn jitter_ms(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some((self.m2 / f64::from(self.count)).sqrt())
+    }
+
+    /// detailed なら (-v) jitter も出す
+    fn print(&self, label: &str, detailed: bool) {
+        let (Some(min), Some(max)) = (self.min, self.max) else {
+            info!("[{}] RTT: 計測なし", label);
+            return;
+        };
+        let avg = self.sum / self.count;
+        info!(
+            "[{}] RTT: min={:.3}ms, avg={:.3}ms, max={:.3}ms",
+            label,
+            as_ms(min),
+            as_ms(avg),
+            as_ms(max)
+        );
+        if detailed && let Some(jitter) = self.jitter_ms() {
+            info!(
+                "[{}] RTT: jitter={:.3}ms (標準偏差, n={})",
+                label, jitter, self.count
+            );
+        }
+    }
+}
+
+fn as_ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// Data を 1 つ送信し、送信した時刻を返す
+async fn send_data(
+    conn: &mut Conn<'_>,
+    no: u32,
+    retry: u32,
+    payload: &Payload,
+) -> Result<Instant, CommError> {
+    let msg = Message::data(no, Peer::Client)
+        .with_retry(retry)
+        .with_label(conn.label)
+        .with_session(conn.session)
+        .with_payload(payload.for_no(no)?);
+    let data = conn.codec.encode(&msg)?;
+    info!(
+        target: PACKET,
+        no,
+        retry,
+        bytes = data.len(),
+        "[{}] 送信: {:?} bytes={}",
+        conn.label,
+        msg,
+        data.len()
+    );
+    // sim の遅延は RTT に含め、--pps の待ち時間は含めないように、待った後で送信前の時刻を返す
+    conn.pace().await;
+    let at = Instant::now();
+    conn.send_paced(&data, no).await?;
+    Ok(at)
+}
+
+/// 生存確認の Ping を送る (no は 0 のままで、Data の no の並びには影響しない)
+async fn send_ping(conn: &mut Conn<'_>) -> Result<(), CommError> {
+    let ping = Message::ping()
+        .with_label(conn.label)
+        .with_session(conn.session);
+    info!(target: PACKET, "[{}] Ping 送信: {:?}", conn.label, ping);
+    conn.send(&ping).await?;
+    Ok(())
+}
+
+/// --bidi のサーバから受信した Push に Ack を返す (no はサーバ側の no)
+async fn send_push_ack(conn: &mut Conn<'_>, no: u32) -> Result<(), CommError> {
+    let ack = Message::ack(no, Peer::Client)
+        .with_label(conn.label)
+        .with_session(conn.session);
+    info!(target: PACKET, no, "[{}] Push の Ack 送信: {:?}", conn.label, ack);
+    conn.send(&ack).await?;
+    Ok(())
+}
+
+/// セッションを打ち切る RST を送る。サーバはこのクライアントの状態を捨てる
+async fn send_rst(conn: &mut Conn<'_>) -> Result<(), CommError> {
+    let rst = Message::rst(Peer::Client)
+        .with_label(conn.label)
+        .with_session(conn.session);
+    warn!("[{}] RST 送信: {:?}", conn.label, rst);
+    conn.send(&rst).await?;
+    Ok(())
+}
+
+/// 応答待ちの no を retry+1 して再送し、送信した時刻を返す。
+/// retry が上限を超えたら RST を送ってあきらめる
+async fn resend_data(
+    conn: &mut Conn<'_>,
+    no: u32,
+    retry: &mut u32,
+    max_retries: u32,
+    payload: &Payload,
+) -> Result<Instant, CommError> {
+    *retry += 1;
+    if *retry > max_retries {
+        error!(
+            no,
+            retries = max_retries,
+            "[{}] no={} gave up after {} retries",
+            conn.label,
+            no,
+            max_retries
+        );
+        send_rst(conn).await?;
+        return Err(CommError::GaveUp {
+            what: format!("no={}", no),
+            retries: max_retries,
+        });
+    }
+    conn.metrics.retransmits += 1;
+    send_data(conn, no, *retry, payload).await
+}
+
+/// クライアント処理
+///
+/// 最大 config.window 個の no を応答待ちのまま同時に送る (window=1 なら stop-and-wait)。
+/// Ctrl-C を受けたら FIN を送って途中で終わり、まとめを出してから CommError::Interrupted を返す。
+/// 終了時にサーバからの応答の受信ログと送受信のカウンタを返す。
+/// 応答が落ちても累積 ACK で届いたとわかった no は、受信ログには入らず metrics.acked にだけ数える。
+/// 送信は config.sim() を通すので、パケットロスや遅延を模擬できる。
+/// config.keepalive を指定すると、応答待ちの間その間隔で Ping を送り、NAT の対応付けを保つ。
+/// config.file を指定すると、そのファイルをチャンクに分けて payload に載せ、チャンク数だけ送る。
+/// config.local_port を指定すると、送信元ポートをそのポートに固定する。
+/// config.local_ip を指定すると、送信元アドレスをそのアドレスに固定する。
+/// サーバが動いておらず connection refused が MAX_REFUSED 回続いたら、CommError::Unreachable を返す。
+/// config.retransmit を指定すると、バックオフせずにその間隔で再送する。
+/// config.deadline を指定すると、最初の送信からその時間内に応答のない no があれば、
+/// max_retries を超えたときと同じく RST を送って CommError::GaveUp を返す。
+/// config.streams が 2 以上なら run_streams で区間ごとに並行に送る
+pub async fn run_client(
+    server_addr: SocketAddr,
+    config: &Config,
+) -> Result<(RecvLog, Metrics), CommError> {
+    if config.streams > 1 {
+        return run_streams(server_addr, config).await;
+    }
+    let socket = bind_local(server_addr, config, config.local_port).await?;
+    run_client_with_socket(socket, server_addr, config).await
+}
+
+/// --streams: start_no から count 個の no を streams 個の連続した区間に分け、
+/// 区間ごとに別のソケットで run_client_with_socket を並行に動かす
+///
+/// サーバはアドレスごとにセッションを分けるので、区間はそれぞれ別のセッションになる。
+/// 終わったら区間ごとの受信ログとカウンタを 1 つにまとめて返す。どれかが失敗したら残りも止めてエラーを返す
+async fn run_streams(
+    server_addr: SocketAddr,
+    config: &Config,
+) -> Result<(RecvLog, Metrics), CommError> {
+    config.validate()?;
+    let label = config.client_label();
+    // 区間が空にならないように、count より多くは分けない
+    let streams = config.streams.min(config.count);
+    info!(
+        streams,
+        "[{}] {} 個の no を {} 本のストリームに分けて送ります", label, config.count, streams
+    );
+
+    let mut tasks = JoinSet::new();
+    let mut offset = 0;
+    for i in 0..streams {
+        let count = config.count / streams + u32::from(i < config.count % streams);
+        // 進捗バーはストリームごとには出さない
+        let stream_config = Config {
+            count,
+            start_no: seq_add(config.start_no, offset),
+            label: Some(format!("{}-{}", label, i + 1)),
+            streams: 1,
+            progress: false,
+            ..config.clone()
+        };
+        offset += count;
+        let socket = bind_local(server_addr, config, 0).await?;
+        tasks.spawn(
+            async move { run_client_with_socket(socket, server_addr, &stream_config).await },
+        );
+    }
+
+    let mut recv_log = RecvLog::new(label)
+        .with_first(config.start_no)
+        .with_expected(seq_add(config.start_no, config.count - 1));
+    let mut metrics = Metrics::default();
+    while let Some(joined) = tasks.join_next().await {
+        let (stream_log, stream_metrics) = joined.map_err(io::Error::other)??;
+        recv_log.merge(&stream_log);
+        metrics += stream_metrics;
+    }
+
+    recv_log.log_summary();
+    if config.hist {
+        recv_log.log_gap_histogram();
+    }
+    metrics.log(label);
+    Ok((recv_log, metrics))
+}
+
+/// ローカル側はサーバと同じアドレスファミリでバインド (local_port=0 なら OS がポートを選ぶ)
+///
+/// config.local_ip を指定していればそのアドレスにバインドする。
+/// ファミリが接続先と違うときや、このホストのアドレスでないときは CommError::BadArgs にする
+async fn bind_local(
+    server_addr: SocketAddr,
+    config: &Config,
+    local_port: u16,
+) -> Result<UdpSocket, CommError> {
+    let local_ip = match config.local_ip {
+        Some(ip) if ip.is_ipv4() != server_addr.is_ipv4() => {
+            return Err(CommError::BadArgs(format!(
+                "local_ip {} と接続先 {} のアドレスファミリが違います",
+                ip, server_addr
+            )));
+        }
+        Some(ip) => ip,
+        None if server_addr.is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let local_addr = SocketAddr::new(local_ip, local_port);
+    UdpSocket::bind(local_addr).await.map_err(|source| {
+        if config.local_ip.is_some() && source.kind() == io::ErrorKind::AddrNotAvailable {
+            CommError::BadArgs(format!(
+                "local_ip {} はこのホストのアドレスではありません",
+                local_ip
+            ))
+        } else {
+            CommError::Bind {
+                addr: local_addr,
+                source,
+            }
+        }
+    })
+}
+
+/// msg を 1 回だけ送り、最初に届いた応答を返す (--send 用)
+///
+/// 再送もセッションの管理もしない。config.timeout までに応答がなければエラーにする
+pub async fn send_once(
+    server_addr: SocketAddr,
+    msg: &Message,
+    config: &Config,
+) -> Result<Message, CommError> {
+    let socket = bind_local(server_addr, config, config.local_port).await?;
+    if let Some(dscp) = config.dscp {
+        apply_dscp(&socket, dscp, config.client_label());
+    }
+    socket.connect(server_addr).await?;
+    let codec = config.codec();
+    socket.send(&codec.encode(msg)?).await?;
+
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let n = time::timeout(config.timeout, socket.recv(&mut buf))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "応答がありませんでした"))??;
+    codec
+        .decode(&buf[..n])
+        .map_err(|e| CommError::Serde(Box::new(e)))
+}
+
+/// サーバに Stat を 1 回送り、応答に載っていたセッション一覧を返す (--stat 用)
+pub async fn query_stat(
+    server_addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<SessionStat>, CommError> {
+    let request = Message::stat(Peer::Client).with_label(config.client_label());
+    let reply = send_once(server_addr, &request, config).await?;
+    if reply.kind != MsgKind::Stat {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Stat の応答ではありません: {:?}", reply.kind),
+        )
+        .into());
+    }
+    Ok(serde_json::from_slice(&reply.payload)?)
+}
+
+/// バインド済みの socket を使う run_client (テストや組み込み用)。socket は server_addr に connect する
+pub async fn run_client_with_socket(
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+    config: &Config,
+) -> Result<(RecvLog, Metrics), CommError> {
+    config.validate()?;
+    let label = config.client_label();
+    let &Config {
+        count,
+        timeout,
+        max_retries,
+        payload_size,
+        window,
+        keepalive,
+        start_no,
+        descending,
+        ..
+    } = config;
+    let payload = match &config.file {
+        Some(path) => {
+            let chunk_size = if payload_size == 0 {
+                DEFAULT_CHUNK_SIZE
+            } else {
+                payload_size
+            };
+            Payload::from_file(path, chunk_size, start_no)?
+        }
+        None => Payload::Pattern(payload_size),
+    };
+    let count = payload.count(count)?;
+    info!(addr = %server_addr, "クライアント起動: サーバ = {}", server_addr);
+
+    // パケットキャプチャと突き合わせられるように、実際に使うアドレスとポートを出す
+    info!("[{}] local addr = {}", label, socket.local_addr()?);
+    if let Some(dscp) = config.dscp {
+        apply_dscp(&socket, dscp, label);
+    }
+    socket.connect(server_addr).await?;
+
+    // 前回の実行の遅れたパケットと区別できるように、実行ごとにセッション ID を決める (0 は使わない)
+    let mut conn = Conn {
+        socket: &socket,
+        label,
+        codec: config.codec(),
+        sim: config.sim(),
+        session: rand::random::<u64>().max(1),
+        metrics: Metrics::default(),
+        hex_dump: config.verbose >= HEX_DUMP_LEVEL,
+        pacer: config.pps.map(pacer),
+        refused: 0,
+    };
+
+    // データ送信の前にセッションを開始する
+    handshake(&mut conn, timeout, max_retries, start_no).await?;
+    info!(
+        addr = %server_addr,
+        session = conn.session,
+        "[{}] セッション開始: サーバ = {}, session={}",
+        label,
+        server_addr,
+        conn.session
+    );
+
+    // 何個目に送るか (0 始まり) で送信位置を管理する。
+    // base: まだ応答が来ていない最初の位置 / next: 次に新規送信する位置
+    // 実際の no は seq_add で求めるので、u32::MAX を超えても 1 に戻って続けられる
+    // (descending なら最後の no から start_no に向かって送る)
+    let no_at = |pos: u32| {
+        if descending {
+            seq_add(start_no, count - 1 - pos)
+        } else {
+            seq_add(start_no, pos)
+        }
+    };
+    // start_no から数えて何番目の no か (count 個の範囲外なら None)
+    let pos_of = |no: u32| {
+        let offset = seq_diff(start_no, no);
+        (offset < count).then(|| {
+            if descending {
+                count - 1 - offset
+            } else {
+                offset
+            }
+        })
+    };
+    let mut base: u32 = 0;
+    let mut next: u32 = 0;
+    let progress = Progress::new(config.shows_progress(), count);
+    // 送信済みで応答待ちの no と、その retry 回数
+    let mut in_flight: BTreeMap<u32, u32> = BTreeMap::new();
+    // 応答が来たが base がまだ追いついていない no
+    let mut acked: BTreeSet<u32> = BTreeSet::new();
+    // 応答待ちの no を最後に送信した時刻 (再送したら再送時刻から RTT を測る)
+    let mut sent_at: HashMap<u32, Instant> = HashMap::new();
+    // 応答待ちの no を最初に送信した時刻 (--deadline-ms の期限はここから数える)
+    let mut first_sent: HashMap<u32, Instant> = HashMap::new();
+    let mut rtt_stats = RttStats::default();
+    let started = Instant::now();
+    let end_at = config.end_at(started);
+    // end_at を過ぎて途中で打ち切ったか
+    let mut expired = false;
+    // Ctrl-C で途中で止めたか
+    let mut interrupted = false;
+    // Pong が返ってきていない Ping の数
+    let mut unanswered_pings: u32 = 0;
+    // Pong や重複応答を受信する前に待っていた応答待ちの期限
+    let mut kept_deadline: Option<Instant> = None;
+
+    // サーバからの応答番号の受信ログ
+    let mut recv_log = RecvLog::new(label)
+        .with_summary_every(config.summary_every)
+        .with_first(start_no)
+        .with_expected(seq_add(start_no, count - 1));
+
+    // --bidi のサーバが送ってくる Push の受信ログ (サーバ側の no なので recv_log とは分ける)
+    let mut push_log =
+        RecvLog::new(&format!("{}-RECV", label)).with_summary_every(config.summary_every);
+
+    // Ctrl-C で中断できるようにする
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    // 受信バッファは使い回す (毎回 &buf[..n] だけを見るので、前の受信の残りは読まない)
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    'send: while base < count {
+        // ウィンドウに空きがあるだけ新しい no を送る
+        while next < count && next - base < window {
+            let no = no_at(next);
+            let at = send_data(&mut conn, no, 0, &payload).await?;
+            in_flight.insert(no, 0);
+            sent_at.insert(no, at);
+            first_sent.insert(no, at);
+            next += 1;
+        }
+
+        // サーバからの応答を待つ (retry が増えるほど長く待つ。retry=0 に戻れば timeout に戻る)
+        // --retransmit-ms なら、増やさずにその間隔で再送し続ける
+        let retry = in_flight.values().copied().max().unwrap_or(0);
+        let wait = config
+            .retransmit
+            .unwrap_or_else(|| backoff(retry, timeout, MAX_BACKOFF.max(timeout)));
+        // --deadline-ms: 応答待ちのうち最初に送ったものから数えて、この時刻までに応答がなければ諦める
+        first_sent.retain(|no, _| in_flight.contains_key(no));
+        let give_up = config.deadline.and_then(|d| {
+            first_sent
+                .iter()
+                .min_by_key(|&(_, &at)| at)
+                .map(|(&no, &at)| (no, at + d))
+        });
+        // Pong や重複応答を受信しただけなら、再送までの期限は延ばさない
+        // (サーバの再送が timeout ごとに届くと、いつまでも再送できなくなる)
+        let deadline = kept_deadline
+            .take()
+            .unwrap_or_else(|| Instant::now() + wait);
+        // 待っている間に keepalive だけ経ったら Ping を送る (応答待ちの期限はそのまま)
+        let mut next_ping = keepalive.map(|k| Instant::now() + k);
+        let received = loop {
+            tokio::select! {
+                r = time::timeout_at(deadline, socket.recv(&mut buf)) => break r,
+                _ = &mut ctrl_c => {
+                    warn!("[{}] Ctrl-C を受信。FIN を送信して終了します。", label);
+                    close(&mut conn, timeout, fin_stats(next, base + acked.len() as u32)).await?;
+                    interrupted = true;
+                    break 'send;
+                }
+                _ = time::sleep_until(give_up.map_or(deadline, |(_, at)| at)), if give_up.is_some() => {
+                    let no = give_up.map_or(0, |(no, _)| no);
+                    let retries = in_flight.get(&no).copied().unwrap_or(0);
+                    error!(
+                        no,
+                        retries,
+                        "[{}] no={} は {}ms 以内に応答がありませんでした (再送 {} 回)。諦めます",
+                        label,
+                        no,
+                        config.deadline.unwrap_or_default().as_millis(),
+                        retries
+                    );
+                    send_rst(&mut conn).await?;
+                    return Err(CommError::GaveUp {
+                        what: format!("no={}", no),
+                        retries,
+                    });
+                }
+                _ = time::sleep_until(end_at.unwrap_or(deadline)), if end_at.is_some() => {
+                    warn!("[{}] --max-duration-secs を過ぎました。FIN を送信して終了します。", label);
+                    close(&mut conn, timeout, fin_stats(next, base + acked.len() as u32)).await?;
+                    expired = true;
+                    break 'send;
+                }
+                _ = time::sleep_until(next_ping.unwrap_or(deadline)), if next_ping.is_some() => {
+                    if unanswered_pings >= KEEPALIVE_MISSES {
+                        warn!(
+                            "[{}] Ping に {} 回続けて Pong がありません。サーバが落ちている可能性があります",
+                            label,
+                            unanswered_pings
+                        );
+                    }
+                    send_ping(&mut conn).await?;
+                    unanswered_pings += 1;
+                    next_ping = keepalive.map(|k| Instant::now() + k);
+                }
+            }
+        };
+        let resend = match received {
+            Ok(Ok(n)) => {
+                conn.on_recv(n);
+                if conn.hex_dump {
+                    log_hex_dump(label, &buf[..n]);
+                }
+                warn_if_truncated(label, n, buf.len());
+                let text = String::from_utf8_lossy(&buf[..n]);
+                match conn.codec.decode(&buf[..n]) {
+                    Ok(reply) => {
+                        let is_echo = reply.kind.is_data_reply()
+                            && matches!(reply.from, Peer::Server)
+                            && reply.session == conn.session
+                            && in_flight.contains_key(&reply.no);
+                        // すでに応答済みの no (送信済みで応答待ちでないもの) への応答。再送が重なると届く
+                        let is_dup_echo = reply.kind.is_data_reply()
+                            && matches!(reply.from, Peer::Server)
+                            && reply.session == conn.session
+                            && !is_echo
+                            && pos_of(reply.no).is_some_and(|pos| pos < next);
+                        let rtt = if is_echo {
+                            sent_at.remove(&reply.no).map(|at| at.elapsed())
+                        } else {
+                            None
+                        };
+                        match rtt {
+                            Some(rtt) => {
+                                info!(
+                                    target: PACKET,
+                                    no = reply.no,
+                                    retry = reply.retry,
+                                    bytes = n,
+                                    "[{}] 受信: {:?} bytes={} rtt={:.3}ms{}",
+                                    label,
+                                    reply,
+                                    n,
+                                    as_ms(rtt),
+                                    reply.one_way_label()
+                                );
+                                rtt_stats.add(rtt);
+                                conn.metrics.on_rtt(rtt);
+                            }
+                            None => info!(
+                                target: PACKET,
+                                no = reply.no,
+                                retry = reply.retry,
+                                bytes = n,
+                                "[{}] 受信: {:?} bytes={}{}",
+                                label,
+                                reply,
+                                n,
+                                reply.one_way_label()
+                            ),
+                        }
+
+                        if is_echo || is_dup_echo {
+                            // 送信済みの no へのデータメッセージ (とその Ack) だけログに記録
+                            // (--corrupt-ack-rate でずれた no のような想定外の応答は入れない)
+                            recv_log.record(reply.no);
+                        }
+
+                        if matches!(reply.kind, MsgKind::Rst)
+                            && matches!(reply.from, Peer::Server)
+                            && reply.session == conn.session
+                        {
+                            // サーバがセッションを捨てたので、こちらも受信ログやウィンドウを捨てて中断する
+                            warn!("[{}] RST 受信: {:?}", label, reply);
+                            return Err(CommError::Reset(Peer::Server));
+                        }
+
+                        if matches!(reply.kind, MsgKind::Nack) && matches!(reply.from, Peer::Server)
+                        {
+                            // サーバが受信できていない no のうち、まだ応答待ちのものだけすぐ再送する
+                            for no in &reply.missing {
+                                if let Some(retry) = in_flight.get_mut(no) {
+                                    let at =
+                                        resend_data(&mut conn, *no, retry, max_retries, &payload)
+                                            .await?;
+                                    sent_at.insert(*no, at);
+                                }
+                            }
+                            false
+                        } else if matches!(reply.kind, MsgKind::Pong)
+                            && matches!(reply.from, Peer::Server)
+                        {
+                            // サーバは生きている。Pong は受信ログにも no の並びにも関係しない
+                            unanswered_pings = 0;
+                            kept_deadline = Some(deadline);
+                            false
+                        } else if matches!(reply.kind, MsgKind::Push)
+                            && matches!(reply.from, Peer::Server)
+                            && reply.session == conn.session
+                        {
+                            // --bidi: サーバが自分から送ってきた Data。記録して Ack を返すだけで、こちらの no の並びには関係しない
+                            push_log.record(reply.no);
+                            send_push_ack(&mut conn, reply.no).await?;
+                            kept_deadline = Some(deadline);
+                            false
+                        } else if is_echo {
+                            // Ack には payload が載っていないので照合しない
+                            let expected = payload.for_no(reply.no)?;
+                            if reply.kind == MsgKind::Data && reply.payload != expected {
+                                warn!(
+                                    no = reply.no,
+                                    "[{}] payload 不一致: no={}, 送信={} バイト, 受信={} バイト",
+                                    label,
+                                    reply.no,
+                                    expected.len(),
+                                    reply.payload.len()
+                                );
+                            }
+
+                            // この no は応答済み。base から連続して応答済みならウィンドウを進める
+                            in_flight.remove(&reply.no);
+                            acked.insert(reply.no);
+                            // 累積 ACK: start_no..=ack (seq_add の順) はサーバが受信済みなので、個別の応答を待たずに応答済みにする
+                            // (descending なら start_no は最後に送るので、最後まで効かない。
+                            // まだ何も受信していなければ ack は start_no の前なので、範囲外になる)
+                            let covered = seq_diff(start_no, reply.ack);
+                            if reply.session == conn.session && covered < count {
+                                for pos in base..next {
+                                    let no = no_at(pos);
+                                    if seq_diff(start_no, no) <= covered
+                                        && in_flight.remove(&no).is_some()
+                                    {
+                                        sent_at.remove(&no);
+                                        acked.insert(no);
+                                    }
+                                }
+                            }
+                            while base < next && acked.remove(&no_at(base)) {
+                                base += 1;
+                            }
+
+                            // 最後の no の応答が来ても、途中に応答待ちが残っていれば FIN の前にすぐ再送する
+                            if reply.no == no_at(count - 1) && !in_flight.is_empty() {
+                                info!(
+                                    "[{}] waiting for {} outstanding acks before FIN",
+                                    label,
+                                    in_flight.len()
+                                );
+                                true
+                            } else {
+                                false
+                            }
+                        } else if is_dup_echo {
+                            // 受信ログで重複として数えるだけで、エラー扱いにも再送にもしない
+                            info!(
+                                target: PACKET,
+                                no = reply.no,
+                                "[{}] 応答済みの no={} の重複応答を無視します",
+                                label,
+                                reply.no
+                            );
+                            kept_deadline = Some(deadline);
+                            false
+                        } else {
+                            // 想定と違うメッセージなら無視して未応答分を再送する
+                            conn.metrics.unexpected += 1;
+                            warn!(
+                                no = reply.no,
+                                "[{}] 想定外のメッセージ (kind={:?}, from={:?}, no={}), リトライします",
+                                label,
+                                reply.kind,
+                                reply.from,
+                                reply.no
+                            );
+                            true
+                        }
+                    }
+                    Err(DecodeError::CrcMismatch) => {
+                        // 壊れたパケットとして捨て、再送に任せる
+                        warn!("[{}] CRC mismatch, discarding", label);
+                        true
+                    }
+                    Err(e @ DecodeError::VersionMismatch { .. }) => {
+                        warn!("[{}] {}", label, e);
+                        true
+                    }
+                    Err(e) => {
+                        warn!("[{}] {} / 生データ: {}", label, e, text);
+                        true
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                conn.on_recv_error(&e)?;
+                true
+            }
+            Err(_) => {
+                // タイムアウト
+                conn.metrics.timeouts += 1;
+                for (no, retry) in &in_flight {
+                    info!(
+                        target: PACKET,
+                        no,
+                        retry = retry + 1,
+                        "[{}] タイムアウト: no={}, retry={} で再送します",
+                        label,
+                        no,
+                        retry + 1
+                    );
+                }
+                true
+            }
+        };
+
+        // start_no から count 個のすべての no の応答を受信してから FIN を送る
+        if base == count && in_flight.is_empty() {
+            info!(
+                "[{}] no={} の応答を受信。FIN を送信して終了します。",
+                label,
+                no_at(count - 1)
+            );
+            close(
+                &mut conn,
+                timeout,
+                fin_stats(next, base + acked.len() as u32),
+            )
+            .await?;
+            break;
+        }
+
+        if resend {
+            // 応答待ちの no だけを retry+1 して再送する
+            for (&no, retry) in in_flight.iter_mut() {
+                let at = resend_data(&mut conn, no, retry, max_retries, &payload).await?;
+                sent_at.insert(no, at);
+            }
+        }
+        progress.update(base, &conn.metrics);
+    }
+    // FIN を送ってループを抜けた回は描き直していないので、最後の状態にしてから閉じる
+    progress.update(base, &conn.metrics);
+    progress.finish();
+
+    let elapsed = started.elapsed();
+
+    // 応答済みは base より前のすべてと、base より先で応答が来た分 (応答そのものが届かなくても累積 ACK で数える)
+    conn.metrics.acked = u64::from(base) + acked.len() as u64;
+    conn.metrics.duplicates = recv_log.duplicates();
+
+    // recv_log は届いた応答の記録なので、サーバへの届き具合 (loss) は acked から出す
+    recv_log.log_summary();
+    info!(
+        "[{}] 応答済み: {}/{} (loss={:.1}%, 応答を受信 {})",
+        label,
+        conn.metrics.acked,
+        count,
+        (u64::from(count) - conn.metrics.acked) as f64 * 100.0 / f64::from(count),
+        recv_log.total()
+    );
+    if push_log.total() > 0 {
+        push_log.log_summary();
+    }
+    if config.hist {
+        recv_log.log_gap_histogram();
+    }
+    rtt_stats.print(label, config.verbose > 0);
+    let metrics = conn.metrics;
+    metrics.log(label);
+    log_throughput(label, u64::from(base), metrics.bytes_sent, elapsed);
+    if expired {
+        return Err(config.max_duration_error());
+    }
+    if interrupted {
+        return Err(CommError::Interrupted);
+    }
+    info!("[{}] 終了", label);
+    Ok((recv_log, metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::FIRST_NO;
+
+    #[tokio::test]
+    async fn server_addr_ipv4() {
+        let addr = server_addr("127.0.0.1", 4000, None).await.unwrap();
+        assert_eq!(addr.to_string(), "127.0.0.1:4000");
+    }
+
+    #[tokio::test]
+    async fn server_addr_ipv6() {
+        let addr = server_addr("::1", 4000, None).await.unwrap();
+        assert!(addr.is_ipv6());
+        assert_eq!(addr.to_string(), "[::1]:4000");
+    }
+
+    #[tokio::test]
+    async fn server_addr_family_mismatch() {
+        assert!(matches!(
+            server_addr("127.0.0.1", 4000, Some(AddrFamily::V6)).await,
+            Err(CommError::Resolve { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_addr_unresolvable() {
+        assert!(matches!(
+            server_addr("no such host", 4000, None).await,
+            Err(CommError::Resolve { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn bind_local_uses_local_ip() {
+        let server: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let config = Config {
+            local_ip: Some("127.0.0.1".parse().unwrap()),
+            ..Config::default()
+        };
+        let socket = bind_local(server, &config, 0).await.unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), config.local_ip.unwrap());
+
+        // ファミリが違うアドレスと、このホストにないアドレス (TEST-NET-1) は引数の誤り
+        for ip in ["::1", "192.0.2.1"] {
+            let config = Config {
+                local_ip: Some(ip.parse().unwrap()),
+                ..Config::default()
+            };
+            assert!(matches!(
+                bind_local(server, &config, 0).await,
+                Err(CommError::BadArgs(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rtt_jitter_is_standard_deviation() {
+        let mut stats = RttStats::default();
+        assert_eq!(stats.jitter_ms(), None);
+        for ms in [10, 20, 30] {
+            stats.add(Duration::from_millis(ms));
+        }
+        let jitter = stats.jitter_ms().unwrap();
+        assert!((jitter - (200.0f64 / 3.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn file_payload_keeps_partial_last_chunk() {
+        let path = std::env::temp_dir().join(format!("udp_tool_chunks_{}", std::process::id()));
+        fs::write(&path, [7u8; 10]).unwrap();
+        let payload = Payload::from_file(&path, 4, FIRST_NO).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(payload.count(100).unwrap(), 3);
+        assert_eq!(payload.for_no(FIRST_NO).unwrap(), vec![7; 4]);
+        assert_eq!(payload.for_no(FIRST_NO + 2).unwrap(), vec![7; 2]);
+        assert!(matches!(
+            payload.for_no(FIRST_NO + 3),
+            Err(CommError::NoChunk { no: 4 })
+        ));
+
+        // u32::MAX の次は 0 を飛ばして 1 なので、3 つ目のチャンクは no=1 に載る
+        let payload = Payload::Chunks {
+            first: u32::MAX - 1,
+            chunks: vec![vec![1], vec![2], vec![3]],
+        };
+        assert_eq!(payload.for_no(u32::MAX).unwrap(), vec![2]);
+        assert_eq!(payload.for_no(1).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn transient_send_errors() {
+        assert!(is_transient(&io::Error::from(io::ErrorKind::WouldBlock)));
+        assert!(is_transient(&io::Error::from(io::ErrorKind::Interrupted)));
+        assert!(!is_transient(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+}
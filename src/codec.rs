@@ -0,0 +1,467 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::Deserialize;
+
+use crate::error::CommError;
+use crate::message::{Message, MsgKind, Peer, default_kind};
+
+/// ワイヤ上のシリアライズ形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Bincode,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "bincode" => Ok(Format::Bincode),
+            _ => Err(format!("不明なフォーマット: {}", s)),
+        }
+    }
+}
+
+/// シリアライズした本体の前に付ける識別子。これで始まらないデータグラムは捨てる
+pub const MAGIC: [u8; 2] = *b"UD";
+
+/// プロトコルのバージョン。Message の形式を変えたら上げる
+pub const PROTOCOL_VERSION: u8 = 7;
+
+/// MAGIC とバージョンを合わせたヘッダの長さ
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// pad_to に届くまで本体の後ろに足すバイト。JSON の空白なので、受信側はパースの前に読み飛ばせる
+/// (bincode は本体の長さが決まっているので、後ろの余りはそもそも読まない)
+const PAD_BYTE: u8 = b' ';
+
+/// 受信データを Message に戻せなかった理由
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    Bincode(bincode::Error),
+    CrcMismatch,
+    /// MAGIC で始まっていない (このツールのデータグラムではない)
+    BadMagic,
+    /// 相手のプロトコルのバージョンが違う
+    VersionMismatch {
+        got: u8,
+        expected: u8,
+    },
+    /// compressed の payload を展開できなかった (壊れているか、MAX_INFLATED より大きい)
+    Inflate(io::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "JSON パースエラー: {}", e),
+            DecodeError::Bincode(e) => write!(f, "bincode デコードエラー: {}", e),
+            DecodeError::CrcMismatch => write!(f, "CRC mismatch"),
+            DecodeError::BadMagic => write!(f, "MAGIC が一致しません"),
+            DecodeError::VersionMismatch { got, expected } => write!(
+                f,
+                "protocol version mismatch: got {} expected {}",
+                got, expected
+            ),
+            DecodeError::Inflate(e) => write!(f, "payload を展開できません: {}", e),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Codec::decode_head で読む、Message の先頭のフィールドだけ (--fast-count 用)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Head {
+    pub no: u32,
+    pub from: Peer,
+    pub kind: MsgKind,
+    pub session: u64,
+}
+
+/// Message の session までのフィールド。bincode は並び順に読むので、Message と同じ順に並べる
+/// (payload 以降は読まない。JSON では並び順によらず、知らないフィールドは読み飛ばす)
+#[derive(Deserialize)]
+struct RawHead<'a> {
+    no: u32,
+    #[serde(rename = "retry")]
+    _retry: u32,
+    from: Peer,
+    #[serde(default, borrow, rename = "label")]
+    _label: Cow<'a, str>,
+    #[serde(default = "default_kind")]
+    kind: MsgKind,
+    #[serde(default)]
+    session: u64,
+}
+
+/// Message とワイヤ上のバイト列の相互変換
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub format: Format,
+    /// true なら crc フィールドを付けて送り、受信時に検証する
+    pub crc: bool,
+    /// 0 でなければ、データグラムがこのバイト数になるまで後ろを埋める (もともと長いものはそのまま)
+    pub pad_to: usize,
+    /// true なら payload を deflate で圧縮して送る (小さくならなければそのまま送る)。
+    /// 受信側はこの設定によらず、compressed の付いた payload を展開する
+    pub compress: bool,
+}
+
+impl Codec {
+    pub fn new(format: Format, crc: bool) -> Self {
+        Self {
+            format,
+            crc,
+            pad_to: 0,
+            compress: false,
+        }
+    }
+
+    /// payload を圧縮して送る
+    pub fn with_compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// 送信するデータグラムを pad_to バイトに揃える (経路の MTU を調べる用)
+    pub fn with_pad_to(mut self, pad_to: usize) -> Self {
+        self.pad_to = pad_to;
+        self
+    }
+
+    /// MAGIC とバージョンのヘッダに続けて、シリアライズした Message を並べる
+    pub fn encode(&self, msg: &Message) -> Result<Vec<u8>, CommError> {
+        let mut data = Vec::with_capacity(HEADER_LEN.max(self.pad_to));
+        data.extend_from_slice(&MAGIC);
+        data.push(PROTOCOL_VERSION);
+        data.extend(self.encode_body(msg)?);
+        if data.len() < self.pad_to {
+            data.resize(self.pad_to, PAD_BYTE);
+        }
+        Ok(data)
+    }
+
+    fn encode_body(&self, msg: &Message) -> Result<Vec<u8>, CommError> {
+        let mut msg = msg.clone();
+        msg.crc = None;
+        if self.compress {
+            compress_payload(&mut msg)?;
+        }
+        let data = self.serialize(&msg)?;
+        if !self.crc {
+            return Ok(data);
+        }
+
+        // crc を空にしたシリアライズ結果に対して CRC を計算する
+        msg.crc = Some(crc32fast::hash(&data));
+        self.serialize(&msg)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Message, DecodeError> {
+        let body = strip_header(data)?;
+        let mut msg = self.deserialize(body)?;
+        if self.crc {
+            // 送信側と同じく crc を空にした形に戻して計算し直す (圧縮したままの payload で計算する)
+            let Some(crc) = msg.crc.take() else {
+                return Err(DecodeError::CrcMismatch);
+            };
+            let body = self.serialize(&msg).map_err(|_| DecodeError::CrcMismatch)?;
+            if crc32fast::hash(&body) != crc {
+                return Err(DecodeError::CrcMismatch);
+            }
+            msg.crc = Some(crc);
+        }
+        if msg.compressed {
+            msg.payload = inflate(&msg.payload).map_err(DecodeError::Inflate)?;
+            msg.compressed = false;
+        }
+        Ok(msg)
+    }
+
+    /// Message を組み立てずに、no / from / kind / session だけを読む (--fast-count 用)
+    ///
+    /// ヘッダは decode と同じく確かめるが、CRC は検証せず、payload も展開しない
+    pub fn decode_head(&self, data: &[u8]) -> Result<Head, DecodeError> {
+        let body = strip_header(data)?;
+        let raw: RawHead<'_> = match self.format {
+            Format::Json => {
+                serde_json::from_slice(body.trim_ascii_end()).map_err(DecodeError::Json)?
+            }
+            Format::Bincode => bincode::deserialize(body).map_err(DecodeError::Bincode)?,
+        };
+        Ok(Head {
+            no: raw.no,
+            from: raw.from,
+            kind: raw.kind,
+            session: raw.session,
+        })
+    }
+
+    fn serialize(&self, msg: &Message) -> Result<Vec<u8>, CommError> {
+        match self.format {
+            Format::Json => Ok(serde_json::to_vec(msg)?),
+            Format::Bincode => Ok(bincode::serialize(msg)?),
+        }
+    }
+
+    /// 後ろに詰め物があってもよい (送信側の pad_to はこちらの設定と関係ない)
+    fn deserialize(&self, data: &[u8]) -> Result<Message, DecodeError> {
+        match self.format {
+            Format::Json => {
+                serde_json::from_slice(data.trim_ascii_end()).map_err(DecodeError::Json)
+            }
+            Format::Bincode => bincode::deserialize(data).map_err(DecodeError::Bincode),
+        }
+    }
+}
+
+/// MAGIC とバージョンのヘッダを確かめて、続く本体を返す
+fn strip_header(data: &[u8]) -> Result<&[u8], DecodeError> {
+    let Some(body) = data.strip_prefix(&MAGIC) else {
+        return Err(DecodeError::BadMagic);
+    };
+    let Some((&version, body)) = body.split_first() else {
+        return Err(DecodeError::BadMagic);
+    };
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::VersionMismatch {
+            got: version,
+            expected: PROTOCOL_VERSION,
+        });
+    }
+    Ok(body)
+}
+
+/// 展開した payload の上限。壊れたデータや細工されたデータでメモリを使い切らないようにする
+const MAX_INFLATED: u64 = 16 * 1024 * 1024;
+
+/// payload を deflate で圧縮し、compressed を付ける。空のときと、小さくならないときはそのままにする
+fn compress_payload(msg: &mut Message) -> io::Result<()> {
+    if msg.payload.is_empty() || msg.compressed {
+        return Ok(());
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&msg.payload)?;
+    let deflated = encoder.finish()?;
+    if deflated.len() < msg.payload.len() {
+        msg.payload = deflated;
+        msg.compressed = true;
+    }
+    Ok(())
+}
+
+/// compress_payload で圧縮した payload を元に戻す (MAX_INFLATED を超えたらエラー)
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_INFLATED + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > MAX_INFLATED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} バイトを超えています", MAX_INFLATED),
+        ));
+    }
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{MsgKind, Peer};
+
+    fn sample() -> Message {
+        Message {
+            no: 7,
+            retry: 0,
+            from: Peer::Client,
+            label: "client-a".to_string(),
+            kind: MsgKind::Data,
+            session: 1,
+            payload: vec![1, 2, 3],
+            missing: Vec::new(),
+            ack: 0,
+            stats: None,
+            sent_at: 0,
+            crc: None,
+            compressed: false,
+        }
+    }
+
+    #[test]
+    fn round_trip_with_header() {
+        for format in [Format::Json, Format::Bincode] {
+            let codec = Codec::new(format, true);
+            let data = codec.encode(&sample()).unwrap();
+            assert!(data.starts_with(&MAGIC));
+            assert_eq!(data[MAGIC.len()], PROTOCOL_VERSION);
+            assert_eq!(codec.decode(&data).unwrap().no, 7);
+        }
+    }
+
+    #[test]
+    fn padded_datagrams_decode_without_padding() {
+        for format in [Format::Json, Format::Bincode] {
+            let padded = Codec::new(format, true).with_pad_to(200);
+            let data = padded.encode(&sample()).unwrap();
+            assert_eq!(data.len(), 200);
+            // 受信側は pad_to を知らなくてよい
+            let msg = Codec::new(format, true).decode(&data).unwrap();
+            assert_eq!(msg.payload, vec![1, 2, 3]);
+        }
+
+        // もともと pad_to より長ければ切り詰めない
+        let short = Codec::new(Format::Json, true).with_pad_to(4);
+        assert!(short.encode(&sample()).unwrap().len() > 4);
+    }
+
+    #[test]
+    fn compressed_payload_round_trips() {
+        let payload: Vec<u8> = b"abcd".iter().copied().cycle().take(1000).collect();
+        let msg = Message {
+            payload: payload.clone(),
+            ..sample()
+        };
+        for format in [Format::Json, Format::Bincode] {
+            let plain = Codec::new(format, true).encode(&msg).unwrap();
+            let compressing = Codec::new(format, true).with_compress(true);
+            let data = compressing.encode(&msg).unwrap();
+            assert!(data.len() < plain.len() / 4);
+
+            // 受信側は compress を指定していなくても展開する
+            let decoded = Codec::new(format, true).decode(&data).unwrap();
+            assert_eq!(decoded.payload, payload);
+            assert!(!decoded.compressed);
+        }
+    }
+
+    #[test]
+    fn incompressible_payload_is_sent_as_is() {
+        let codec = Codec::new(Format::Bincode, true).with_compress(true);
+        // 3 バイトは deflate のヘッダより小さい
+        let data = codec.encode(&sample()).unwrap();
+        assert_eq!(
+            data,
+            Codec::new(Format::Bincode, true).encode(&sample()).unwrap()
+        );
+
+        let mut msg = sample();
+        compress_payload(&mut msg).unwrap();
+        assert!(!msg.compressed);
+        assert_eq!(msg.payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn corrupt_compressed_payload_is_rejected() {
+        let codec = Codec::new(Format::Json, false);
+        let msg = Message {
+            payload: vec![0xff; 16],
+            compressed: true,
+            ..sample()
+        };
+        let data = codec.encode(&msg).unwrap();
+        assert!(matches!(codec.decode(&data), Err(DecodeError::Inflate(_))));
+    }
+
+    #[test]
+    fn decode_head_reads_the_leading_fields() {
+        let msg = Message {
+            session: 42,
+            payload: vec![b'x'; 500],
+            ..sample()
+        };
+        let expected = Head {
+            no: 7,
+            from: Peer::Client,
+            kind: MsgKind::Data,
+            session: 42,
+        };
+        for format in [Format::Json, Format::Bincode] {
+            for codec in [
+                Codec::new(format, true),
+                Codec::new(format, false).with_pad_to(1200),
+                Codec::new(format, true).with_compress(true),
+            ] {
+                let data = codec.encode(&msg).unwrap();
+                assert_eq!(codec.decode_head(&data).unwrap(), expected);
+            }
+        }
+
+        let codec = Codec::new(Format::Json, true);
+        let mut data = codec.encode(&sample()).unwrap();
+        data[MAGIC.len()] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            codec.decode_head(&data),
+            Err(DecodeError::VersionMismatch { .. })
+        ));
+    }
+
+    /// decode と decode_head の速さを比べる (cargo test --release -- --ignored --nocapture bench_)
+    #[test]
+    #[ignore]
+    fn bench_decode_head_vs_decode() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        const ROUNDS: u32 = 100_000;
+        let msg = Message {
+            payload: (0..1024).map(|i| i as u8).collect(),
+            ..sample()
+        };
+        for format in [Format::Json, Format::Bincode] {
+            let codec = Codec::new(format, true);
+            let data = codec.encode(&msg).unwrap();
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                black_box(codec.decode(black_box(&data)).unwrap());
+            }
+            let full = start.elapsed() / ROUNDS;
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                black_box(codec.decode_head(black_box(&data)).unwrap());
+            }
+            let head = start.elapsed() / ROUNDS;
+
+            println!(
+                "{:?} ({} バイト): decode {:?} / decode_head {:?} ({:.1} 倍)",
+                format,
+                data.len(),
+                full,
+                head,
+                full.as_secs_f64() / head.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_version_and_missing_magic() {
+        let codec = Codec::new(Format::Json, true);
+        let mut data = codec.encode(&sample()).unwrap();
+        data[MAGIC.len()] = PROTOCOL_VERSION + 1;
+        let err = codec.decode(&data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "protocol version mismatch: got {} expected {}",
+                PROTOCOL_VERSION + 1,
+                PROTOCOL_VERSION
+            )
+        );
+
+        let body = serde_json::to_vec(&sample()).unwrap();
+        assert!(matches!(codec.decode(&body), Err(DecodeError::BadMagic)));
+    }
+}
@@ -0,0 +1,525 @@
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::codec::{Codec, Format};
+use crate::error::CommError;
+use crate::message::FIRST_NO;
+use crate::sim::NetSim;
+use crate::{
+    DEFAULT_BIND, DEFAULT_COUNT, DEFAULT_MAX_RETRIES, DEFAULT_PAYLOAD_SIZE, DEFAULT_PORT,
+    DEFAULT_SESSION_TIMEOUT_MS, DEFAULT_TIMEOUT_MS, DEFAULT_WINDOW, RECV_BUF_SIZE,
+};
+
+/// ホスト名を名前解決したときに優先するアドレスファミリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrFamily {
+    V4,
+    V6,
+}
+
+impl AddrFamily {
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddrFamily::V4 => addr.is_ipv4(),
+            AddrFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// "10.0.0.0/8" や "::1/128" の形のアドレス範囲。プレフィックス長を省くと 1 アドレスだけ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// ip がこの範囲に入っているか。IPv4 射影アドレス (::ffff:a.b.c.d) は IPv4 として比べる
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.canonical(), ip.to_canonical()) {
+            ((IpAddr::V4(net), prefix), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            ((IpAddr::V6(net), prefix), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// 比べる相手と同じく、IPv4 射影の範囲 (::ffff:a.b.c.d/96 以上) を IPv4 の範囲に直す
+    fn canonical(&self) -> (IpAddr, u8) {
+        match self.addr {
+            IpAddr::V6(net) if self.prefix >= 96 => match net.to_ipv4_mapped() {
+                Some(net) => (IpAddr::V4(net), self.prefix - 96),
+                None => (self.addr, self.prefix),
+            },
+            _ => (self.addr, self.prefix),
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("IP アドレスではありません: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|&p| p <= max).ok_or_else(|| {
+                format!(
+                    "プレフィックス長は 0 から {} で指定してください: {}",
+                    max, s
+                )
+            })?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// クライアント・サーバの設定をまとめたもの。main でコマンドラインから一度だけ組み立てる
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// サーバのポート番号 (クライアントは接続先、サーバは待ち受け)
+    pub port: u16,
+    /// サーバが port に加えて待ち受けるポート番号
+    pub extra_ports: Vec<u16>,
+    /// サーバが待ち受けるアドレス
+    pub bind: IpAddr,
+    /// サーバが bind の代わりに [::] で IPv4 と IPv6 の両方を待ち受ける
+    pub dual_stack: bool,
+    /// クライアントが送るメッセージ数
+    pub count: u32,
+    /// 応答待ちタイムアウト
+    pub timeout: Duration,
+    /// 1 メッセージあたりの再送回数の上限
+    pub max_retries: u32,
+    /// Data に載せる payload のサイズ (バイト)
+    pub payload_size: usize,
+    /// シリアライズ形式
+    pub format: Format,
+    /// CRC を付与・検証するか
+    pub crc: bool,
+    /// 応答待ちのまま送れるメッセージ数
+    pub window: u32,
+    /// 受信ログを書き出す CSV のパス (サーバはセッションごとに、ファイル名にセッション ID を入れて分ける)
+    pub log_csv: Option<PathBuf>,
+    /// メッセージごとのログを出さない
+    pub quiet: bool,
+    /// 最初のクライアントの FIN でサーバを終了する
+    pub once: bool,
+    /// この時間受信がないセッションをサーバが捨てる
+    pub session_timeout: Duration,
+    /// 送信パケットを捨てる確率 (テスト用)
+    pub drop_rate: f64,
+    /// drop_rate / jitter の乱数の種
+    pub seed: Option<u64>,
+    /// 送信のたびに待つ時間 (テスト用)
+    pub delay: Duration,
+    /// delay に足すランダムな待ち時間の上限 (テスト用)
+    pub jitter: Duration,
+    /// 応答待ちが続くときに Ping を送る間隔
+    pub keepalive: Option<Duration>,
+    /// 終了時に受信ログのまとめを JSON で標準出力に出す
+    pub json_summary: bool,
+    /// サーバがセッションを持たず、受信したデータをそのまま返す
+    pub echo: bool,
+    /// クライアントが送るファイル (チャンクに分け、チャンク数を count とする)
+    pub file: Option<PathBuf>,
+    /// サーバが受信した payload を no 順に書き出すファイル
+    pub out: Option<PathBuf>,
+    /// クライアントがバインドする送信元ポート (0 なら OS が選ぶ)
+    pub local_port: u16,
+    /// クライアントがバインドする送信元アドレス (None なら OS が選ぶ。マルチホームで出口を決める用)
+    pub local_ip: Option<IpAddr>,
+    /// 接続先の名前解決で使うアドレスファミリ (None なら最初に解決できたもの)
+    pub family: Option<AddrFamily>,
+    /// ログの詳しさ (HEX_DUMP_LEVEL 以上なら受信データを 16 進ダンプで出す)
+    pub verbose: u8,
+    /// サーバが Data の応答を送る前に待つ時間 (テスト用)
+    pub ack_delay: Duration,
+    /// サーバがタイムアウト時に直前の応答を再送しない
+    pub no_server_resend: bool,
+    /// ログの接頭辞・受信ログ・送信メッセージに使う名前 (None なら CLIENT / SERVER)
+    pub label: Option<String>,
+    /// サーバがクライアントのアドレスごとに受信済みの no を保存し、起動時に読み込むファイル
+    pub state_file: Option<PathBuf>,
+    /// セッション終了時に、受信した no の間隔のヒストグラムを出す
+    pub hist: bool,
+    /// クライアントが最初に送る no (start_no から count 個送る)
+    pub start_no: u32,
+    /// クライアントが 1 秒あたりに送るパケット数の上限 (再送や SYN / FIN も数える。None なら制限しない)
+    pub pps: Option<u32>,
+    /// サーバが順番を飛ばした no を受信したらエラーにする (once なら終了する)
+    pub strict_order: bool,
+    /// 送信パケットに付ける DSCP (0-63。None なら OS のまま)
+    pub dscp: Option<u8>,
+    /// サーバが Data をエコーせず、payload のない Ack で応答する
+    pub ack_only: bool,
+    /// サーバが受け付ける送信元のアドレス範囲 (空ならすべて受け付ける)
+    pub allow: Vec<Cidr>,
+    /// クライアントが no を大きい方から順に送る (受信側が昇順を前提にしていないかの確認用)
+    pub descending: bool,
+    /// 受信ログのまとめを何個受信するごとに出すか (セッション終了時には必ず出す)
+    pub summary_every: u32,
+    /// サーバもクライアントの Data を受信するたびに、自分の no で Push を送る
+    pub bidi: bool,
+    /// 送信するデータグラムをこのバイト数になるまで埋める (0 なら埋めない)
+    pub pad_to: usize,
+    /// 進み具合にかかわらず、この時間が過ぎたらまとめを出して終わる (None なら制限しない)
+    pub max_duration: Option<Duration>,
+    /// クライアントが応答済みの no の数と再送率を進捗バーで出す (--quiet や端末でないときは出さない)
+    pub progress: bool,
+    /// クライアントが no の範囲をこの数の連続した区間に分け、区間ごとに別のソケットで並行に送る
+    pub streams: u32,
+    /// サーバがこの確率で、Data の応答の no を 1 つずらして返す (クライアントの想定外の応答の処理を試す用)
+    pub corrupt_ack_rate: f64,
+    /// サーバが Data の応答を何回続けて送るか (2 以上でクライアントの重複の扱いを試す)
+    pub reply_count: u32,
+    /// payload を deflate で圧縮して送る (小さくならない payload はそのまま送る)
+    pub compress: bool,
+    /// クライアントが Data を再送する間隔 (None なら timeout から指数バックオフ)
+    pub retransmit: Option<Duration>,
+    /// クライアントが 1 つの no の応答を最初の送信から待つ上限 (None なら max_retries だけで諦める)
+    pub deadline: Option<Duration>,
+    /// サーバが Data の no だけを読んで受信ログに記録し、Ack で応答する (CRC と payload は見ない)
+    pub fast_count: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            extra_ports: Vec::new(),
+            bind: DEFAULT_BIND,
+            dual_stack: false,
+            count: DEFAULT_COUNT,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            max_retries: DEFAULT_MAX_RETRIES,
+            payload_size: DEFAULT_PAYLOAD_SIZE,
+            format: Format::Json,
+            crc: true,
+            window: DEFAULT_WINDOW,
+            log_csv: None,
+            quiet: false,
+            once: false,
+            session_timeout: Duration::from_millis(DEFAULT_SESSION_TIMEOUT_MS),
+            drop_rate: 0.0,
+            seed: None,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            keepalive: None,
+            json_summary: false,
+            echo: false,
+            file: None,
+            out: None,
+            local_port: 0,
+            local_ip: None,
+            family: None,
+            verbose: 0,
+            ack_delay: Duration::ZERO,
+            no_server_resend: false,
+            label: None,
+            state_file: None,
+            hist: false,
+            start_no: FIRST_NO,
+            pps: None,
+            strict_order: false,
+            dscp: None,
+            ack_only: false,
+            allow: Vec::new(),
+            descending: false,
+            summary_every: 1,
+            bidi: false,
+            pad_to: 0,
+            max_duration: None,
+            progress: false,
+            streams: 1,
+            corrupt_ack_rate: 0.0,
+            reply_count: 1,
+            compress: false,
+            retransmit: None,
+            deadline: None,
+            fast_count: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn codec(&self) -> Codec {
+        Codec::new(self.format, self.crc)
+            .with_pad_to(self.pad_to)
+            .with_compress(self.compress)
+    }
+
+    /// max_duration が過ぎる時刻 (start から数える)
+    pub(crate) fn end_at(&self, start: tokio::time::Instant) -> Option<tokio::time::Instant> {
+        self.max_duration.map(|d| start + d)
+    }
+
+    /// end_at を過ぎて打ち切ったときのエラー
+    pub(crate) fn max_duration_error(&self) -> CommError {
+        CommError::MaxDuration {
+            secs: self.max_duration.unwrap_or_default().as_secs(),
+        }
+    }
+
+    pub fn sim(&self) -> NetSim {
+        NetSim::new(self.drop_rate, self.seed)
+            .with_delay(self.delay, self.jitter)
+            .with_corrupt_rate(self.corrupt_ack_rate)
+    }
+
+    /// 0 にできない値や範囲外の値がないか確かめる
+    pub fn validate(&self) -> Result<(), CommError> {
+        let bad = |msg: &str| Err(CommError::BadArgs(msg.to_string()));
+        if self.count == 0 {
+            return bad("count には 1 以上を指定してください");
+        }
+        if self.timeout.is_zero() {
+            return bad("timeout には 0 より大きい値を指定してください");
+        }
+        if self.start_no == 0 {
+            return bad("start_no には 1 以上を指定してください (0 は制御メッセージ用)");
+        }
+        if self.fast_count && (self.echo || self.out.is_some() || self.strict_order) {
+            return bad("fast_count は echo / out / strict_order と一緒に指定できません");
+        }
+        if self.dual_stack && self.bind != DEFAULT_BIND {
+            return bad("dual_stack と bind は一緒に指定できません");
+        }
+        if self.window == 0 {
+            return bad("window には 1 以上を指定してください");
+        }
+        if self.session_timeout.is_zero() {
+            return bad("session_timeout には 0 より大きい値を指定してください");
+        }
+        if self.dscp.is_some_and(|d| d > 63) {
+            return bad("dscp には 0 から 63 の値を指定してください");
+        }
+        if self.pad_to > RECV_BUF_SIZE {
+            return bad(&format!(
+                "pad_to には {} 以下を指定してください",
+                RECV_BUF_SIZE
+            ));
+        }
+        if self.reply_count == 0 {
+            return bad("reply_count には 1 以上を指定してください");
+        }
+        if self.streams == 0 {
+            return bad("streams には 1 以上を指定してください");
+        }
+        if self.streams > 1 && self.file.is_some() {
+            return bad("streams と file は同時に指定できません");
+        }
+        if self.streams > 1 && self.local_port != 0 {
+            return bad("streams ではソケットごとにポートが要るので local_port を指定できません");
+        }
+        if self.summary_every == 0 {
+            return bad("summary_every には 1 以上を指定してください");
+        }
+        if self.pps == Some(0) {
+            return bad("pps には 1 以上を指定してください");
+        }
+        if self.max_duration.is_some_and(|d| d.is_zero()) {
+            return bad("max_duration には 0 より大きい値を指定してください");
+        }
+        if self.retransmit.is_some_and(|r| r.is_zero()) {
+            return bad("retransmit には 0 より大きい値を指定してください");
+        }
+        if self.deadline.is_some_and(|d| d.is_zero()) {
+            return bad("deadline には 0 より大きい値を指定してください");
+        }
+        if self.keepalive.is_some_and(|k| k.is_zero()) {
+            return bad("keepalive には 0 より大きい値を指定してください");
+        }
+        if !(0.0..=1.0).contains(&self.drop_rate) {
+            return bad("drop_rate には 0.0 から 1.0 の値を指定してください");
+        }
+        if !(0.0..=1.0).contains(&self.corrupt_ack_rate) {
+            return bad("corrupt_ack_rate には 0.0 から 1.0 の値を指定してください");
+        }
+        Ok(())
+    }
+
+    /// 進捗バーを実際に出すか。--quiet のときと、標準エラー出力が端末でないときは出さない
+    pub fn shows_progress(&self) -> bool {
+        self.progress && !self.quiet && io::stderr().is_terminal()
+    }
+
+    /// クライアントとして動くときの名前
+    pub fn client_label(&self) -> &str {
+        self.label.as_deref().unwrap_or("CLIENT")
+    }
+
+    /// サーバとして動くときの名前
+    pub fn server_label(&self) -> &str {
+        self.label.as_deref().unwrap_or("SERVER")
+    }
+
+    /// サーバが addr からのデータグラムを受け付けるか (allow が空ならすべて)
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(addr.ip()))
+    }
+
+    /// サーバが待ち受けるアドレス
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// サーバが待ち受けるすべてのアドレス (bind_addr() と extra_ports)
+    pub fn bind_addrs(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.port)
+            .chain(self.extra_ports.iter().copied())
+            .map(|port| SocketAddr::new(self.bind, port))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_matches_constants() {
+        let config = Config::default();
+        assert_eq!(config.count, DEFAULT_COUNT);
+        assert_eq!(config.timeout, Duration::from_millis(DEFAULT_TIMEOUT_MS));
+        assert_eq!(config.bind_addr().to_string(), "0.0.0.0:4000");
+        assert!(config.codec().crc);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn label_overrides_default_names() {
+        let config = Config::default();
+        assert_eq!(config.client_label(), "CLIENT");
+        assert_eq!(config.server_label(), "SERVER");
+
+        let config = Config {
+            label: Some("client-a".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(config.client_label(), "client-a");
+        assert_eq!(config.server_label(), "client-a");
+    }
+
+    #[test]
+    fn validate_rejects_zero_window() {
+        let config = Config {
+            window: 0,
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+    }
+
+    #[test]
+    fn validate_rejects_zero_start_no() {
+        let config = Config {
+            start_no: 0,
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+    }
+
+    #[test]
+    fn validate_rejects_dual_stack_with_bind() {
+        let config = Config {
+            dual_stack: true,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = Config {
+            dual_stack: true,
+            bind: "127.0.0.1".parse().unwrap(),
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+    }
+
+    #[test]
+    fn validate_rejects_zero_retransmit_or_deadline() {
+        let config = Config {
+            retransmit: Some(Duration::ZERO),
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+
+        let config = Config {
+            deadline: Some(Duration::ZERO),
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+    }
+
+    #[test]
+    fn validate_rejects_streams_with_fixed_port_or_file() {
+        let config = Config {
+            streams: 4,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = Config {
+            streams: 4,
+            local_port: 5000,
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+
+        let config = Config {
+            streams: 4,
+            file: Some(PathBuf::from("data.bin")),
+            ..Config::default()
+        };
+        assert!(matches!(config.validate(), Err(CommError::BadArgs(_))));
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+
+        let host: Cidr = "::1".parse().unwrap();
+        assert!(host.contains("::1".parse().unwrap()));
+        assert!(!host.contains("127.0.0.1".parse().unwrap()));
+
+        // --dual-stack では IPv4 のクライアントも射影アドレスで届く
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert!(mapped.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(mapped.contains("10.1.2.3".parse().unwrap()));
+        assert!(!mapped.contains("::ffff:11.0.0.1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn bind_addrs_include_extra_ports() {
+        let config = Config {
+            port: 4000,
+            extra_ports: vec![4001, 4002],
+            ..Config::default()
+        };
+        let ports: Vec<u16> = config.bind_addrs().iter().map(|a| a.port()).collect();
+        assert_eq!(ports, [4000, 4001, 4002]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+use crate::message::Peer;
+
+/// クライアント・サーバの処理が失敗した理由
+#[derive(Debug, Error)]
+pub enum CommError {
+    /// ソケットをバインドできなかった
+    #[error("{addr} にバインドできません: {source}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    /// 接続先のホスト名を名前解決できなかった
+    #[error("{host} を名前解決できません: {source}")]
+    Resolve {
+        host: String,
+        #[source]
+        source: io::Error,
+    },
+    /// 送受信やファイル書き出しの失敗
+    #[error("I/O エラー: {0}")]
+    Io(#[from] io::Error),
+    /// メッセージやまとめをシリアライズできなかった
+    #[error("シリアライズエラー: {0}")]
+    Serde(#[source] Box<dyn Error + Send + Sync>),
+    /// 再送回数の上限に達してもサーバから応答がなかった
+    #[error("{what} gave up after {retries} retries")]
+    GaveUp { what: String, retries: u32 },
+    /// サーバのポートに届かない (ICMP port unreachable による ConnectionRefused が続いた)
+    #[error("サーバに届きません ({refused} 回続けて connection refused)")]
+    Unreachable { refused: u32 },
+    /// 相手から RST を受信してセッションが打ち切られた
+    #[error("{0:?} がセッションをリセットしました")]
+    Reset(Peer),
+    /// --strict-order で、連続して受信済みの次より先の no を受信した
+    #[error("順序違反: no={no} を受信しましたが、次は no={expected} のはずです")]
+    OutOfOrder { no: u32, expected: u32 },
+    /// --file のチャンクの範囲外の no を送ろうとした
+    #[error("no={no} に対応するファイルのチャンクがありません")]
+    NoChunk { no: u32 },
+    /// --max-duration-secs の時間が過ぎたので、終わる前に打ち切った
+    #[error("{secs} 秒経っても終わらなかったので打ち切りました")]
+    MaxDuration { secs: u64 },
+    /// クライアントの転送を Ctrl-C で途中で止めた
+    #[error("Ctrl-C で中断しました")]
+    Interrupted,
+    /// 設定や引数の値が不正
+    #[error("引数が不正です: {0}")]
+    BadArgs(String),
+}
+
+impl From<serde_json::Error> for CommError {
+    fn from(e: serde_json::Error) -> Self {
+        CommError::Serde(Box::new(e))
+    }
+}
+
+impl From<bincode::Error> for CommError {
+    fn from(e: bincode::Error) -> Self {
+        CommError::Serde(e)
+    }
+}
@@ -0,0 +1,189 @@
+use socket2::SockRef;
+use std::fmt::Write;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+mod client;
+mod codec;
+mod config;
+mod error;
+mod message;
+mod metrics;
+mod progress;
+mod recv_log;
+mod server;
+mod sim;
+mod stream;
+
+pub use client::{query_stat, run_client, run_client_with_socket, send_once, server_addr};
+pub use codec::{Codec, DecodeError, Format, Head, MAGIC, PROTOCOL_VERSION};
+pub use config::{AddrFamily, Cidr, Config};
+pub use error::CommError;
+pub use message::{FIN_NO, FIRST_NO, FinStats, Message, MsgKind, Peer, next_no, seq_add, seq_diff};
+pub use metrics::Metrics;
+pub use recv_log::{RecvLog, SeqNo, Summary};
+pub use server::{
+    SessionStat, run_server, run_server_with_hook, run_server_with_socket, run_server_with_sockets,
+};
+pub use sim::NetSim;
+pub use stream::{message_stream, message_stream_with_codec};
+
+/// メッセージ 1 つごとに出るログの target。--quiet ではこの target だけ warn 以上に絞る
+pub const PACKET: &str = "packet";
+
+/// ポート番号を指定しなかった場合に使うポート
+pub const DEFAULT_PORT: u16 = 4000;
+
+/// バインドアドレスを指定しなかった場合にサーバが待ち受けるアドレス (全インターフェース)
+pub const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+/// 送信数を指定しなかった場合にクライアントが送るメッセージ数
+pub const DEFAULT_COUNT: u32 = 100;
+
+/// 応答待ちタイムアウトを指定しなかった場合の値 (ミリ秒)
+pub const DEFAULT_TIMEOUT_MS: u64 = 100;
+
+/// 再送回数の上限を指定しなかった場合の値 (実質無制限)
+pub const DEFAULT_MAX_RETRIES: u32 = u32::MAX;
+
+/// payload サイズを指定しなかった場合の値 (バイト)
+pub const DEFAULT_PAYLOAD_SIZE: usize = 0;
+
+/// --file のチャンクサイズ。payload サイズを指定しなかった場合に使う (バイト)
+pub const DEFAULT_CHUNK_SIZE: usize = 1024;
+
+/// ウィンドウサイズを指定しなかった場合の値 (1 なら stop-and-wait)
+pub const DEFAULT_WINDOW: u32 = 1;
+
+/// 放置されたセッションをサーバが捨てるまでの時間を指定しなかった場合の値 (ミリ秒)
+pub const DEFAULT_SESSION_TIMEOUT_MS: u64 = 30_000;
+
+/// 受信バッファのサイズ (UDP で送れる最大ペイロード長)
+pub const RECV_BUF_SIZE: usize = 65507;
+
+/// --verbose をこの回数以上指定すると、受信したデータグラムを 16 進ダンプで出す
+pub const HEX_DUMP_LEVEL: u8 = 2;
+
+/// 受信サイズがバッファいっぱいなら、データが切り詰められた可能性を警告する
+pub(crate) fn warn_if_truncated(label: &str, n: usize, buf_len: usize) {
+    if n >= buf_len {
+        warn!(
+            "[{}] 受信データがバッファ ({} バイト) に収まらず切り詰められた可能性があります",
+            label, buf_len
+        );
+    }
+}
+
+/// 送信するパケットの DSCP を設定する (IPv4 は IP_TOS、IPv6 は IPV6_TCLASS)
+///
+/// 対応していない環境や権限がない場合もあるので、失敗しても警告を出して続ける
+pub(crate) fn apply_dscp(socket: &UdpSocket, dscp: u8, label: &str) {
+    // DSCP は TOS / Traffic Class バイトの上位 6 ビット
+    let tos = u32::from(dscp) << 2;
+    let sock = SockRef::from(socket);
+    let result = match socket.local_addr() {
+        Ok(addr) if addr.is_ipv6() => set_tclass_v6(&sock, tos),
+        Ok(_) => sock.set_tos_v4(tos),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => info!(
+            "[{}] DSCP={} (TOS=0x{:02x}) を設定しました",
+            label, dscp, tos
+        ),
+        Err(e) => warn!("[{}] DSCP={} を設定できません: {}", label, dscp, e),
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(sock: &SockRef<'_>, tclass: u32) -> io::Result<()> {
+    sock.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_sock: &SockRef<'_>, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "この OS では IPV6_TCLASS を設定できません",
+    ))
+}
+
+/// 経過時間あたりのメッセージ数とバイト数を 1 行で出す
+pub(crate) fn log_throughput(label: &str, msgs: u64, bytes: u64, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let (msg_rate, kb_rate) = if secs > 0.0 {
+        (msgs as f64 / secs, bytes as f64 / 1024.0 / secs)
+    } else {
+        (0.0, 0.0)
+    };
+    info!(
+        "[{}] throughput: {:.1} msg/s, {:.1} KB/s",
+        label, msg_rate, kb_rate
+    );
+}
+
+/// 「オフセット  16 進  |ASCII|」の形で 16 バイトずつ 1 行にする
+pub(crate) fn hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, "{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), ascii);
+    }
+    out
+}
+
+/// パースする前の受信データを 16 進ダンプで出す
+pub(crate) fn log_hex_dump(label: &str, data: &[u8]) {
+    info!(
+        "[{}] 受信 {} バイト:\n{}",
+        label,
+        data.len(),
+        hex_dump(data).trim_end()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_dump_shows_offset_hex_and_ascii() {
+        let dump = hex_dump(b"UD\x01{\"no\":1}\n0123456789");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "00000000  55 44 01 7b 22 6e 6f 22 3a 31 7d 0a 30 31 32 33  |UD.{\"no\":1}.0123|"
+        );
+        assert!(lines[1].starts_with("00000010  34 35 36 37 38 39 "));
+        assert!(lines[1].ends_with("|456789|"));
+        assert_eq!(hex_dump(&[]), "");
+    }
+}
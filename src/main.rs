@@ -1,316 +1,535 @@
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
 use std::env;
 use std::error::Error;
-use std::net::SocketAddr;
-use tokio::net::UdpSocket;
-use tokio::time::{self, Duration};
-
-const PORT: u16 = 4000;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum MsgKind {
-    Data,
-    Fin,
-}
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
-fn default_kind() -> MsgKind {
-    MsgKind::Data
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+use udp_tool::{
+    AddrFamily, Cidr, CommError, Config, DEFAULT_BIND, DEFAULT_COUNT, DEFAULT_MAX_RETRIES,
+    DEFAULT_PAYLOAD_SIZE, DEFAULT_PORT, DEFAULT_SESSION_TIMEOUT_MS, DEFAULT_TIMEOUT_MS,
+    DEFAULT_WINDOW, FIRST_NO, Format, Message, PACKET, query_stat, run_client,
+    run_client_with_socket, run_server, run_server_with_socket, send_once, server_addr,
+};
+
+/// selftest でクライアントが終わってからサーバの終了を待つ時間
+const SELFTEST_SERVER_WAIT: Duration = Duration::from_secs(5);
+
+/// クライアントの host を省いたときに使うサーバのアドレス (例: 10.0.0.5:4000)
+const SERVER_ENV: &str = "SIMPLE_UDP_SERVER";
+
+/// --bind / -p を省いたときにサーバが待ち受けるアドレス (例: 0.0.0.0:4000)
+const BIND_ENV: &str = "SIMPLE_UDP_BIND";
+
+/// UDP で連番のメッセージを送り、サーバからの応答で受信状況を確認するツール
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    mode: Mode,
+    #[command(flatten)]
+    opts: Opts,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message {
-    no: u32,
-    retry: u32,
-    from: String, // "client" or "server"
-    #[serde(default = "default_kind")]
-    kind: MsgKind, // "data" or "fin"
+/// 旧形式の -s / -c <ip> でも呼べるように short_flag を付けている
+#[derive(Subcommand)]
+enum Mode {
+    /// サーバとして待ち受ける
+    #[command(short_flag = 's')]
+    Server,
+    /// クライアントとしてサーバにメッセージを送る
+    #[command(short_flag = 'c')]
+    Client {
+        /// サーバのホスト名または IP アドレス (IPv4 / IPv6)。省くと環境変数 SIMPLE_UDP_SERVER (アドレス:ポート) を使う
+        host: Option<String>,
+        /// この JSON の Message を 1 回だけ送り、届いた応答を JSON で標準出力に出して終わる (再送しない)
+        #[arg(long)]
+        send: Option<String>,
+        /// サーバのセッション一覧 (アドレス、受信数、最後に受信してからの時間など) を問い合わせ、JSON で標準出力に出して終わる
+        #[arg(long, conflicts_with = "send")]
+        stat: bool,
+    },
+    /// 同じプロセス内のループバックでサーバとクライアントを動かし、PASS / FAIL を出す
+    Selftest,
 }
 
-/// 受信した no を記録して、「どこからどこまで受信済みか」を表示するための構造体
-#[derive(Default)]
-struct RecvLog {
-    label: String,
-    received: BTreeSet<u32>,
+/// どちらのモードでも、サブコマンドの前後どちらに書いてもよいオプション
+#[derive(Args)]
+struct Opts {
+    /// ポート番号 (デフォルト: 4000)。サーバは複数指定 (-p 4000 -p 4001 や -p 4000,4001) するとすべてで待ち受ける。
+    /// 指定すると SIMPLE_UDP_SERVER / SIMPLE_UDP_BIND のポートより優先する
+    #[arg(short, long, global = true, value_delimiter = ',')]
+    port: Vec<u16>,
+    /// 送信するメッセージ数
+    #[arg(
+        long,
+        global = true,
+        default_value_t = DEFAULT_COUNT,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    count: u32,
+    /// 応答待ちタイムアウト (ミリ秒)
+    #[arg(
+        long,
+        global = true,
+        default_value_t = DEFAULT_TIMEOUT_MS,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    timeout_ms: u64,
+    /// 1 メッセージあたりの再送回数の上限 (デフォルト: 無制限)
+    #[arg(long, global = true)]
+    max_retries: Option<u32>,
+    /// Data に載せる payload のサイズ (バイト)
+    #[arg(long, global = true, default_value_t = DEFAULT_PAYLOAD_SIZE)]
+    payload_size: usize,
+    /// CRC の付与・検証をしない (旧フォーマットとの相互運用用)
+    #[arg(long, global = true)]
+    no_crc: bool,
+    /// シリアライズ形式 (json | bincode)
+    #[arg(long, global = true, default_value = "json")]
+    format: Format,
+    /// 応答待ちのまま送れるメッセージ数
+    #[arg(
+        long,
+        global = true,
+        default_value_t = DEFAULT_WINDOW,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    window: u32,
+    /// 終了時に受信ログを CSV で書き出す (サーバはセッションごとに、ファイル名にセッション ID を入れて分ける)
+    #[arg(long, global = true)]
+    log_csv: Option<PathBuf>,
+    /// ログを詳しくする (-v で RTT の jitter、-vv で受信したデータグラムの 16 進ダンプも出す)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// メッセージごとのログを出さない (まとめとエラーは出す)
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// 最初のクライアントの FIN でサーバを終了する
+    #[arg(long, global = true)]
+    once: bool,
+    /// この時間受信がないセッションを捨てる (ミリ秒)
+    #[arg(
+        long,
+        global = true,
+        default_value_t = DEFAULT_SESSION_TIMEOUT_MS,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    session_timeout_ms: u64,
+    /// サーバが待ち受けるアドレス (デフォルト: 0.0.0.0)。省くと環境変数 SIMPLE_UDP_BIND (アドレス:ポート) を使う
+    #[arg(long, global = true)]
+    bind: Option<IpAddr>,
+    /// サーバが [::] で IPv4 と IPv6 の両方を待ち受ける (IPV6_V6ONLY を外せなければ 2 つのソケットに分ける)
+    #[arg(long, global = true, conflicts_with = "bind")]
+    dual_stack: bool,
+    /// 送信パケットをこの確率 (0.0-1.0) で捨てる (テスト用)
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_rate)]
+    drop_rate: f64,
+    /// --drop-rate / --jitter-ms の乱数の種 (指定すると毎回同じパケットが落ちる)
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// 送信のたびにこの時間待つ (ミリ秒、テスト用)
+    #[arg(long, global = true, default_value_t = 0)]
+    delay_ms: u64,
+    /// 0 からこの時間までのランダムな待ちを --delay-ms に足す (ミリ秒)。
+    /// delay + jitter が --timeout-ms に近いと、応答が間に合わず再送が増える
+    #[arg(long, global = true, default_value_t = 0)]
+    jitter_ms: u64,
+    /// サーバが Data の応答をこの時間だけ遅らせて送る (ミリ秒、テスト用)。
+    /// クライアントの --timeout-ms より少し長くすると、不要な再送を再現できる
+    #[arg(long, global = true, default_value_t = 0)]
+    ack_delay_ms: u64,
+    /// サーバがタイムアウトしても直前の応答を再送しない (クライアントへの応答だけを返す)
+    #[arg(long, global = true)]
+    no_server_resend: bool,
+    /// 応答待ちが続くとき、この間隔で Ping を送る (ミリ秒、デフォルト: 送らない)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive_ms: Option<u64>,
+    /// 終了時に受信ログのまとめを JSON 1 行で標準出力に出す
+    #[arg(long, global = true)]
+    json_summary: bool,
+    /// サーバがセッションを持たず、受信したデータをログに出してそのまま返す (プロトコルの確認用)
+    #[arg(long, global = true)]
+    echo: bool,
+    /// このファイルを --payload-size (未指定なら 1024) バイトずつに分けて送る。--count は無視する
+    #[arg(long, global = true)]
+    file: Option<PathBuf>,
+    /// サーバが受信した payload を no 順につなげてこのファイルに書き出す
+    #[arg(long, global = true)]
+    out: Option<PathBuf>,
+    /// クライアントの送信元ポートを固定する (ファイアウォールのルール用。デフォルト: OS が選ぶ)
+    #[arg(long, global = true, default_value_t = 0)]
+    local_port: u16,
+    /// クライアントの送信元アドレスを固定する (マルチホームのホストで出ていくインターフェースを決める。デフォルト: OS が選ぶ)
+    #[arg(long, global = true)]
+    local_ip: Option<IpAddr>,
+    /// ホスト名を IPv4 のアドレスに解決する
+    #[arg(short = '4', long, global = true, conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// ホスト名を IPv6 のアドレスに解決する
+    #[arg(short = '6', long, global = true)]
+    ipv6: bool,
+    /// ログの [CLIENT] / [SERVER] の代わりに使う名前 (複数のクライアントのログを見分ける用)。送信するメッセージにも載せる
+    #[arg(long, global = true)]
+    label: Option<String>,
+    /// サーバが受信済みの no をクライアントのアドレスごとにこのファイルに定期的に保存し、起動時に読み込む (再起動しても続きから受信する)
+    #[arg(long, global = true)]
+    state_file: Option<PathBuf>,
+    /// 終了時に、受信した no の間隔 (1 なら連続) ごとの回数を出す。損失が単発か固まっているかが分かる
+    #[arg(long, global = true)]
+    hist: bool,
+    /// クライアントが最初に送る no。--count と合わせて start..start+count を送る (0 は制御メッセージ用なので使えない)
+    #[arg(
+        long,
+        global = true,
+        default_value_t = FIRST_NO,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    start_no: u32,
+    /// クライアントが 1 秒あたりに送るパケット数の上限 (再送や SYN / FIN も数える。デフォルト: 制限しない)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pps: Option<u32>,
+    /// サーバが順番を飛ばした no を受信したらエラーをログに出す (--once ならエラーで終了する)
+    #[arg(long, global = true)]
+    strict_order: bool,
+    /// 送信パケットの DSCP (0-63) を設定する (QoS の確認用。設定できなければ警告して続ける)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u8).range(0..=63))]
+    dscp: Option<u8>,
+    /// サーバが Data をエコーする代わりに、payload のない Ack で応答する (大きな payload で帯域を半分にする)
+    #[arg(long, global = true)]
+    ack_only: bool,
+    /// サーバがこの範囲 (例: 10.0.0.0/8) の送信元からだけ受け付ける。複数指定できる (デフォルト: すべて)
+    #[arg(long, global = true, value_delimiter = ',')]
+    allow: Vec<Cidr>,
+    /// クライアントが no を大きい方から (start_no + count - 1 から start_no まで) 順に送る
+    #[arg(long, global = true)]
+    descending: bool,
+    /// 受信ログのまとめを N 個受信するごとに出す (大きな転送では 100 などにする。セッション終了時には必ず出す)
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    summary_every: u32,
+    /// 双方向モード: サーバもクライアントの Data を受信するたびに自分の no で Push を送り、クライアントはそれを受信ログに記録する (サーバ側で指定する)
+    #[arg(long, global = true)]
+    bidi: bool,
+    /// 送信するデータグラムを、JSON などの長さによらずこのバイト数になるまで後ろを埋める (経路 MTU の調査用。デフォルト: 埋めない)
+    #[arg(long, global = true, default_value_t = 0)]
+    pad_to: usize,
+    /// 進み具合にかかわらず、この秒数が過ぎたらまとめを出して終わる (終了コード 1。デフォルト: 制限しない)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    max_duration_secs: Option<u64>,
+    /// クライアントが応答済みの no / --count と再送率を進捗バーで出す (--quiet や端末でないときは出さない)
+    #[arg(long, global = true)]
+    progress: bool,
+    /// no の範囲をこの数の連続した区間に分け、区間ごとに別のソケットで並行に送る (サーバには --once を付けないこと)。
+    /// --pps と --window は区間ごとにかかる
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    streams: u32,
+    /// サーバがこの確率 (0.0-1.0) で、Data の応答の no を 1 つずらして返す (テスト用)。
+    /// クライアントは想定外の応答として数えて再送する (--window 1 で使う。大きいと次の no の応答と区別できない)
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_rate)]
+    corrupt_ack_rate: f64,
+    /// サーバが Data の応答をこの回数だけ続けて送る (テスト用)。クライアントは 2 回目からを重複として数える
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    reply_count: u32,
+    /// 送信する payload を deflate で圧縮する (小さくならなければ圧縮しない)。受信側は指定しなくても展開する
+    #[arg(long, global = true)]
+    compress: bool,
+    /// クライアントが応答のない Data をこの間隔で再送する (ミリ秒。デフォルト: --timeout-ms から倍々に延ばす)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    retransmit_ms: Option<u64>,
+    /// 1 つの no の応答を、最初に送ってからこの時間まで待って諦める (ミリ秒。デフォルト: --max-retries だけで決める)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    deadline_ms: Option<u64>,
+    /// サーバが Data の no だけを読んで数え、Ack で応答する (CRC と payload を見ないぶん速い。ロスの計測用)
+    #[arg(long, global = true)]
+    fast_count: bool,
 }
 
-impl RecvLog {
-    fn new(label: &str) -> Self {
-        Self {
-            label: label.to_string(),
-            received: BTreeSet::new(),
+impl Opts {
+    fn config(&self) -> Config {
+        Config {
+            port: self.port.first().copied().unwrap_or(DEFAULT_PORT),
+            extra_ports: self.port.iter().skip(1).copied().collect(),
+            bind: self.bind.unwrap_or(DEFAULT_BIND),
+            dual_stack: self.dual_stack,
+            count: self.count,
+            timeout: Duration::from_millis(self.timeout_ms),
+            max_retries: self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            payload_size: self.payload_size,
+            format: self.format,
+            crc: !self.no_crc,
+            window: self.window,
+            log_csv: self.log_csv.clone(),
+            quiet: self.quiet,
+            verbose: self.verbose,
+            once: self.once,
+            session_timeout: Duration::from_millis(self.session_timeout_ms),
+            drop_rate: self.drop_rate,
+            seed: self.seed,
+            delay: Duration::from_millis(self.delay_ms),
+            jitter: Duration::from_millis(self.jitter_ms),
+            ack_delay: Duration::from_millis(self.ack_delay_ms),
+            no_server_resend: self.no_server_resend,
+            label: self.label.clone(),
+            state_file: self.state_file.clone(),
+            hist: self.hist,
+            start_no: self.start_no,
+            pps: self.pps,
+            strict_order: self.strict_order,
+            dscp: self.dscp,
+            ack_only: self.ack_only,
+            allow: self.allow.clone(),
+            descending: self.descending,
+            summary_every: self.summary_every,
+            bidi: self.bidi,
+            pad_to: self.pad_to,
+            max_duration: self.max_duration_secs.map(Duration::from_secs),
+            progress: self.progress,
+            streams: self.streams,
+            corrupt_ack_rate: self.corrupt_ack_rate,
+            reply_count: self.reply_count,
+            compress: self.compress,
+            retransmit: self.retransmit_ms.map(Duration::from_millis),
+            deadline: self.deadline_ms.map(Duration::from_millis),
+            fast_count: self.fast_count,
+            keepalive: self.keepalive_ms.map(Duration::from_millis),
+            json_summary: self.json_summary,
+            echo: self.echo,
+            file: self.file.clone(),
+            out: self.out.clone(),
+            local_port: self.local_port,
+            local_ip: self.local_ip,
+            // -4 / -6 がなければ、--local-ip と同じファミリに解決する
+            family: match (self.ipv4, self.ipv6, self.local_ip) {
+                (true, _, _) => Some(AddrFamily::V4),
+                (_, true, _) => Some(AddrFamily::V6),
+                (_, _, Some(IpAddr::V4(_))) => Some(AddrFamily::V4),
+                (_, _, Some(IpAddr::V6(_))) => Some(AddrFamily::V6),
+                (_, _, None) => None,
+            },
         }
     }
+}
 
-    fn record(&mut self, no: u32) {
-        self.received.insert(no);
-
-        let summary = self.build_ranges_summary();
-        let min = self.received.first().copied().unwrap_or(0);
-        let max = self.received.last().copied().unwrap_or(0);
-        let total = self.received.len();
-
-        println!(
-            "[{}] 受信ログ: min={}, max={}, total={}, ranges=[{}]",
-            self.label, min, max, total, summary
-        );
-    }
-
-    /// 受信済みの no を、連続区間ごとに "1-5, 7-10, 12" のような文字列にする
-    fn build_ranges_summary(&self) -> String {
-        let mut ranges = Vec::new();
-        let mut start: Option<u32> = None;
-        let mut prev: Option<u32> = None;
-
-        for &n in &self.received {
-            match (start, prev) {
-                (None, _) => {
-                    start = Some(n);
-                    prev = Some(n);
-                }
-                (Some(s), Some(p)) => {
-                    if n == p + 1 {
-                        // 連続
-                        prev = Some(n);
-                    } else {
-                        // 途切れたのでひと区間確定
-                        if s == p {
-                            ranges.push(format!("{}", s));
-                        } else {
-                            ranges.push(format!("{}-{}", s, p));
-                        }
-                        start = Some(n);
-                        prev = Some(n);
-                    }
-                }
-                _ => {}
-            }
-        }
-
-        if let (Some(s), Some(p)) = (start, prev) {
-            if s == p {
-                ranges.push(format!("{}", s));
-            } else {
-                ranges.push(format!("{}-{}", s, p));
-            }
-        }
-
-        ranges.join(", ")
+/// 0.0 から 1.0 の確率をパースする
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s
+        .parse()
+        .map_err(|_| format!("数値ではありません: {}", s))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err("0.0 から 1.0 の値を指定してください".to_string());
     }
+    Ok(rate)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() < 2 {
-        eprintln!("使い方:");
-        eprintln!("  サーバ:  {} -s", args[0]);
-        eprintln!("  クライアント: {} -c <server_ip>", args[0]);
-        std::process::exit(1);
+    let cli = Cli::parse();
+    let mut config = cli.opts.config();
+
+    // RUST_LOG でレベルを絞れるようにする (未指定なら info 以上を標準出力へ)
+    let mut filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    // 進捗バーを出すときも、流れるログでバーが見えなくならないように同じく抑える
+    let progress = matches!(cli.mode, Mode::Client { .. }) && config.shows_progress();
+    if config.quiet || progress {
+        // メッセージごとのログだけ抑え、まとめとエラーは残す
+        filter = filter.add_directive(format!("{}=warn", PACKET).parse()?);
     }
-
-    match args[1].as_str() {
-        "-s" => {
-            run_server().await?;
-        }
-        "-c" => {
-            if args.len() < 3 {
-                eprintln!("クライアントモードにはサーバIPが必要です。");
-                eprintln!("例: {} -c 127.0.0.1", args[0]);
-                std::process::exit(1);
-            }
-            let ip = &args[2];
-            let addr: SocketAddr = format!("{}:{}", ip, PORT).parse()?;
-            run_client(addr).await?;
-        }
-        _ => {
-            eprintln!("不明なオプション: {}", args[1]);
-            eprintln!("  -s : サーバモード");
-            eprintln!("  -c : クライアントモード");
-            std::process::exit(1);
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal())
+        .init();
+
+    let result = match cli.mode {
+        Mode::Server => match apply_bind_env(&cli.opts, &mut config) {
+            Ok(()) => run_server(&config).await.map(|_| ()),
+            Err(e) => Err(e),
+        },
+        Mode::Client {
+            host,
+            send: json,
+            stat,
+        } => match target(host.as_deref(), &cli.opts, &config).await {
+            Ok(addr) => match json {
+                Some(json) => send(addr, &json, &config).await,
+                None if stat => print_stat(addr, &config).await,
+                None => client(addr, &config).await,
+            },
+            Err(e) => Err(e),
+        },
+        Mode::Selftest => selftest(&config).await,
+    };
+    if let Err(e) = result {
+        if let CommError::BadArgs(_) = e {
+            usage_error(&e);
         }
+        error!("{}", e);
+        // Ctrl-C で止めたときは、シェルで SIGINT により終わったときと同じ 130 (完了した転送と見分けられるように)
+        let code = if let CommError::Interrupted = e {
+            130
+        } else {
+            1
+        };
+        std::process::exit(code);
     }
 
     Ok(())
 }
 
-/// クライアント処理
-async fn run_client(server_addr: SocketAddr) -> Result<(), Box<dyn Error>> {
-    println!("クライアント起動: サーバ = {}", server_addr);
-
-    // ローカル側は適当なポートでバインド
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(server_addr).await?;
-
-    let mut no: u32 = 1;
-    let mut retry: u32 = 0;
-
-    // サーバからの応答番号の受信ログ
-    let mut recv_log = RecvLog::new("CLIENT");
-
-    while no <= 100 {
-        let msg = Message {
-            no,
-            retry,
-            from: "client".to_string(),
-            kind: MsgKind::Data,
-        };
-        let data = serde_json::to_vec(&msg)?;
-        println!("[CLIENT] 送信: {:?}", msg);
-        socket.send(&data).await?;
-
-        let mut buf = [0u8; 1024];
-
-        // サーバからの応答を100ms待つ
-        match time::timeout(Duration::from_millis(100), socket.recv(&mut buf)).await {
-            Ok(Ok(n)) => {
-                let text = String::from_utf8_lossy(&buf[..n]);
-                match serde_json::from_str::<Message>(&text) {
-                    Ok(reply) => {
-                        println!("[CLIENT] 受信: {:?}", reply);
-
-                        if let MsgKind::Data = reply.kind {
-                            // データメッセージだけログに記録
-                            recv_log.record(reply.no);
-                        }
-
-                        if matches!(reply.kind, MsgKind::Data)
-                            && reply.from == "server"
-                            && reply.no == no
-                        {
-                            // この no に対する応答が来たので次の番号へ
-                            if no == 100 {
-                                println!(
-                                    "[CLIENT] no=100 の応答を受信。FIN を送信して終了します。"
-                                );
+/// 引数の誤りを、clap が弾いたときと同じく使い方と合わせて標準エラー出力に出し、終了コード 2 で終わる
+///
+/// 使い方のコマンド名は clap が args[0] から決める (空なら Cli の名前を使う) ので、名前を変えても崩れない
+fn usage_error(e: &CommError) -> ! {
+    eprintln!("error: {}\n\n{}", e, Cli::command().render_usage());
+    std::process::exit(2);
+}
 
-                                // FIN を送信（no=0 は特別な意味として使用）
-                                let fin = Message {
-                                    no: 0,
-                                    retry: 0,
-                                    from: "client".to_string(),
-                                    kind: MsgKind::Fin,
-                                };
-                                let fin_data = serde_json::to_vec(&fin)?;
-                                println!("[CLIENT] FIN 送信: {:?}", fin);
-                                socket.send(&fin_data).await?;
+/// name の環境変数を "アドレス:ポート" としてパースする (なければ None)
+fn env_addr(name: &str) -> Result<Option<SocketAddr>, CommError> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| {
+            CommError::BadArgs(format!(
+                "{} はアドレス:ポートの形で指定してください (例: 10.0.0.5:4000): {}",
+                name, value
+            ))
+        }),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(CommError::BadArgs(format!(
+            "{} が UTF-8 ではありません",
+            name
+        ))),
+    }
+}
 
-                                break;
-                            }
-                            no += 1;
-                            retry = 0;
-                        } else {
-                            // 想定と違うメッセージなら無視してリトライカウントを増やす
-                            eprintln!(
-                                "[CLIENT] 想定外のメッセージ (kind={:?}, from={}, no={}), リトライします",
-                                reply.kind, reply.from, reply.no
-                            );
-                            retry += 1;
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("[CLIENT] JSON パースエラー: {} / 生データ: {}", e, text);
-                        retry += 1;
-                    }
-                }
-            }
-            Ok(Err(e)) => {
-                eprintln!("[CLIENT] recv エラー: {}", e);
-                retry += 1;
-            }
-            Err(_) => {
-                // タイムアウト
-                retry += 1;
-                println!(
-                    "[CLIENT] タイムアウト: no={}, retry={} で再送します",
-                    no, retry
-                );
-            }
+/// --bind / -p を省いていれば、SIMPLE_UDP_BIND のアドレスとポートで待ち受ける (--dual-stack ならポートだけ)
+fn apply_bind_env(opts: &Opts, config: &mut Config) -> Result<(), CommError> {
+    if let Some(addr) = env_addr(BIND_ENV)? {
+        if opts.bind.is_none() && !opts.dual_stack {
+            config.bind = addr.ip();
+        }
+        if opts.port.is_empty() {
+            config.port = addr.port();
         }
     }
-
-    println!("[CLIENT] 終了");
     Ok(())
 }
 
-/// サーバ処理
-async fn run_server() -> Result<(), Box<dyn Error>> {
-    let bind_addr = format!("0.0.0.0:{}", PORT);
-    let socket = UdpSocket::bind(&bind_addr).await?;
-    println!("[SERVER] 起動: {}", bind_addr);
-
-    let mut last_msg: Option<Message> = None;
-    let mut last_addr: Option<SocketAddr> = None;
-
-    // クライアントから受信した no のログ
-    let mut recv_log = RecvLog::new("SERVER-RECV");
-
-    loop {
-        let mut buf = [0u8; 1024];
+/// 接続先のサーバ。host を省いたら SIMPLE_UDP_SERVER を使う (-p を指定していればポートはそちら)
+async fn target(host: Option<&str>, opts: &Opts, config: &Config) -> Result<SocketAddr, CommError> {
+    let Some(host) = host else {
+        let mut addr = env_addr(SERVER_ENV)?.ok_or_else(|| {
+            CommError::BadArgs(format!(
+                "サーバのホストを引数か環境変数 {} で指定してください",
+                SERVER_ENV
+            ))
+        })?;
+        if let Some(&port) = opts.port.first() {
+            addr.set_port(port);
+        }
+        info!(addr = %addr, "[{}] {} のサーバ {} を使います", config.client_label(), SERVER_ENV, addr);
+        return Ok(addr);
+    };
+    let addr = server_addr(host, config.port, config.family).await?;
+    info!(addr = %addr, "[{}] {} を {} に解決しました", config.client_label(), host, addr);
+    Ok(addr)
+}
 
-        // クライアントからのデータを 100ms 待つ
-        match time::timeout(Duration::from_millis(100), socket.recv_from(&mut buf)).await {
-            // 受信できた
-            Ok(Ok((n, addr))) => {
-                let text = String::from_utf8_lossy(&buf[..n]);
-                match serde_json::from_str::<Message>(&text) {
-                    Ok(msg) => {
-                        println!("[SERVER] 受信 from {}: {:?}", addr, msg);
+/// クライアントを実行し、終了後に受信ログを書き出して acked_report_line を出す
+async fn client(addr: SocketAddr, config: &Config) -> Result<(), CommError> {
+    let started = Instant::now();
+    let (recv_log, metrics) = run_client(addr, config).await?;
+    if let Some(path) = &config.log_csv {
+        recv_log.export_csv(path)?;
+    }
+    if config.json_summary {
+        let summary = recv_log.acked_summary(&metrics, started.elapsed());
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+    println!(
+        "{}",
+        recv_log.acked_report_line(&metrics, started.elapsed())
+    );
+    Ok(())
+}
 
-                        match msg.kind {
-                            MsgKind::Data => {
-                                // 受信ログを更新
-                                recv_log.record(msg.no);
+/// --send: json の Message を 1 回送り、応答を 1 行の JSON で出す
+async fn send(addr: SocketAddr, json: &str, config: &Config) -> Result<(), CommError> {
+    let msg: Message = serde_json::from_str(json)?;
+    let reply = send_once(addr, &msg, config).await?;
+    println!("{}", serde_json::to_string(&reply)?);
+    Ok(())
+}
 
-                                // クライアントから来た no をそのまま返す
-                                let reply = Message {
-                                    no: msg.no,
-                                    retry: 0, // 新規応答なので retry=0
-                                    from: "server".to_string(),
-                                    kind: MsgKind::Data,
-                                };
-                                let data = serde_json::to_vec(&reply)?;
-                                socket.send_to(&data, addr).await?;
-                                println!("[SERVER] 送信 to {}: {:?}", addr, reply);
+/// --stat: サーバのセッション一覧を 1 行の JSON で出す
+async fn print_stat(addr: SocketAddr, config: &Config) -> Result<(), CommError> {
+    let stats = query_stat(addr, config).await?;
+    println!("{}", serde_json::to_string(&stats)?);
+    Ok(())
+}
 
-                                // 再送用に記録
-                                last_msg = Some(reply);
-                                last_addr = Some(addr);
-                            }
-                            MsgKind::Fin => {
-                                println!("[SERVER] FIN 受信 from {}: {:?}", addr, msg);
-                                println!("[SERVER] セッションを終了します。");
-                                // ここでプロセス終了（ループを抜ける）
-                                return Ok(());
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("[SERVER] JSON パースエラー: {} / 生データ: {}", e, text);
-                    }
-                }
-            }
-            // recv_from 自体のエラー
-            Ok(Err(e)) => {
-                eprintln!("[SERVER] recv_from エラー: {}", e);
-            }
-            // タイムアウト: 直前のメッセージを retry+1 して再送
-            Err(_) => {
-                if let (Some(mut msg), Some(addr)) = (last_msg.clone(), last_addr) {
-                    // 直前に送信したメッセージが Data の場合だけ再送（FIN は再送しない）
-                    if matches!(msg.kind, MsgKind::Data) {
-                        msg.retry += 1;
-                        let data = serde_json::to_vec(&msg)?;
-                        println!("[SERVER] タイムアウト、再送 to {}: {:?}", addr, msg);
-                        socket.send_to(&data, addr).await?;
-                        last_msg = Some(msg);
-                    }
+/// ループバック上で 1 往復ぶん動かし、すべての no が届いたかを確かめる。FAIL なら終了コード 1 で終わる
+async fn selftest(config: &Config) -> Result<(), CommError> {
+    let config = Config {
+        once: true,
+        ..config.clone()
+    };
+    // ポートは OS に選ばせるので、本物のサーバが動いていてもぶつからない
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server_socket.local_addr()?;
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let result = match run_client_with_socket(client_socket, server_addr, &config).await {
+        // 累積 ACK で進むので、クライアントがすべての応答を受信しているとは限らない。サーバ側の受信で判定する
+        Ok(_) => match time::timeout(SELFTEST_SERVER_WAIT, server).await {
+            Ok(Ok(Ok((Some(server_log), _)))) => {
+                if server_log.total() == config.count as usize && server_log.missing(1).is_empty() {
+                    Ok(())
                 } else {
-                    // まだ何も送ったことがない場合は何もしない
+                    Err(format!(
+                        "server received {} of {}",
+                        server_log.total(),
+                        config.count
+                    ))
                 }
             }
+            Ok(Ok(Ok((None, _)))) => Err("server returned no session".to_string()),
+            Ok(Ok(Err(e))) => Err(e.to_string()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("server did not finish".to_string()),
+        },
+        Err(e) => Err(e.to_string()),
+    };
+
+    match result {
+        Ok(()) => {
+            println!("PASS");
+            Ok(())
+        }
+        Err(reason) => {
+            println!("FAIL: {}", reason);
+            std::process::exit(1);
         }
     }
 }
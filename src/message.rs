@@ -0,0 +1,373 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MsgKind {
+    Syn,
+    Data,
+    Fin,
+    Nack,
+    /// 無通信が続くときにクライアントが送る生存確認 (no は使わない)
+    Ping,
+    /// Ping に対するサーバの応答
+    Pong,
+    /// セッションの異常終了。受け取った側はそのセッションの状態をすべて捨てる
+    Rst,
+    /// --ack-only のサーバが Data の代わりに返す応答。no (と累積 ACK) だけで payload は載せない。
+    /// --bidi ではクライアントが Push に返す応答にも使う
+    Ack,
+    /// --bidi のサーバが自分から送る Data。no はサーバ側で数えるので、クライアントの no とは関係ない
+    Push,
+    /// --stat のクライアントが送るセッション一覧の問い合わせ。サーバは同じ kind で、payload に JSON の一覧を載せて返す
+    Stat,
+}
+
+impl MsgKind {
+    /// クライアントの Data に対するサーバの応答か (Data のエコーか Ack)
+    pub fn is_data_reply(self) -> bool {
+        matches!(self, MsgKind::Data | MsgKind::Ack)
+    }
+}
+
+/// メッセージの送り手
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Peer {
+    Client,
+    Server,
+}
+
+/// FIN に載せる、クライアントが送信・受信した no の数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FinStats {
+    /// 送信した no の数 (再送は数えない)
+    pub sent: u32,
+    /// 応答を受信した no の数
+    pub acked: u32,
+}
+
+pub(crate) fn default_kind() -> MsgKind {
+    MsgKind::Data
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub no: u32,
+    pub retry: u32,
+    pub from: Peer, // "client" or "server"
+    /// 送り手の名前 (--label、未指定なら "CLIENT" / "SERVER")。複数のクライアントを見分けるのに使う
+    #[serde(default)]
+    pub label: String,
+    #[serde(default = "default_kind")]
+    pub kind: MsgKind, // "syn", "data", "fin", "nack", "ping", "pong", "rst", "ack", "push" or "stat"
+    /// クライアントの実行ごとに決まるセッション ID (0 なら不明)。サーバは受信したものをそのまま返す
+    #[serde(default)]
+    pub session: u64,
+    #[serde(default)]
+    pub payload: Vec<u8>,
+    /// NACK のときだけ使う、サーバがまだ受信していない no の一覧
+    #[serde(default)]
+    pub missing: Vec<u32>,
+    /// サーバの Data の応答で使う累積 ACK。1..=ack をすべて受信済み (0 ならまだ何もない)
+    #[serde(default)]
+    pub ack: u32,
+    /// FIN のときだけ使う、クライアントから見たセッションのまとめ (サーバが自分の受信ログと突き合わせる)
+    #[serde(default)]
+    pub stats: Option<FinStats>,
+    /// 送信側の時計での送信時刻 (UNIX エポックからのミリ秒。0 なら不明)
+    #[serde(default)]
+    pub sent_at: u64,
+    /// crc を None にしてシリアライズしたバイト列の CRC32 (--no-crc のときは None のまま)
+    #[serde(default)]
+    pub crc: Option<u32>,
+    /// payload が deflate で圧縮されているか (--compress)。Codec がエンコード時に付け、デコード時に戻す
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+/// Data の no の最初の値。0 は SYN / FIN / NACK / Ping などの制御メッセージ用に予約している
+pub const FIRST_NO: u32 = 1;
+
+/// FIN と FIN-ACK に使う no。
+/// Data の no は FIRST_NO から数えて seq_add で進め、0 は飛ばすので、0 なら Data の no と重ならない
+/// (変えるときも Data の範囲 1..=u32::MAX に入らない値にすること)
+pub const FIN_NO: u32 = 0;
+
+/// no から k 個先の no を返す。no の空間は 1..=u32::MAX で、u32::MAX の次は 0 を飛ばして 1 に戻る
+pub fn seq_add(no: u32, k: u32) -> u32 {
+    let space = u64::from(u32::MAX);
+    ((u64::from(no) - 1 + u64::from(k)) % space) as u32 + 1
+}
+
+/// no の次の no (u32::MAX の次は 1)
+pub fn next_no(no: u32) -> u32 {
+    seq_add(no, 1)
+}
+
+/// from から seq_add で何個進めると to になるか (seq_add(from, seq_diff(from, to)) == to)。
+/// to が 0 なら u32::MAX - from (どの Data の no とも重ならないほど遠い) を返す
+pub fn seq_diff(from: u32, to: u32) -> u32 {
+    if to >= from {
+        to - from
+    } else {
+        u32::MAX - from + to
+    }
+}
+
+/// 現在時刻を UNIX エポックからのミリ秒で返す
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Message {
+    /// kind のメッセージを作る。no と from 以外は空 (label も session も未設定) で、sent_at は今の時刻。
+    /// 残りは with_* で足す
+    pub fn new(kind: MsgKind, no: u32, from: Peer) -> Self {
+        Self {
+            no,
+            retry: 0,
+            from,
+            label: String::new(),
+            kind,
+            session: 0,
+            payload: Vec::new(),
+            missing: Vec::new(),
+            ack: 0,
+            stats: None,
+            sent_at: unix_millis(),
+            crc: None,
+            compressed: false,
+        }
+    }
+
+    /// セッション開始の SYN。クライアントは最初に送る no を載せ、サーバの応答は 0 にする
+    pub fn syn(no: u32, from: Peer) -> Self {
+        Self::new(MsgKind::Syn, no, from)
+    }
+
+    /// Data (クライアントの送信と、サーバのエコー)
+    pub fn data(no: u32, from: Peer) -> Self {
+        Self::new(MsgKind::Data, no, from)
+    }
+
+    /// FIN と FIN-ACK。no は FIN_NO
+    pub fn fin(from: Peer) -> Self {
+        Self::new(MsgKind::Fin, FIN_NO, from)
+    }
+
+    /// payload を載せない応答 (--ack-only のサーバの応答と、--bidi のクライアントが Push に返す応答)
+    pub fn ack(no: u32, from: Peer) -> Self {
+        Self::new(MsgKind::Ack, no, from)
+    }
+
+    /// サーバがまだ受信していない no を知らせる NACK
+    pub fn nack(missing: Vec<u32>) -> Self {
+        Self {
+            missing,
+            ..Self::new(MsgKind::Nack, 0, Peer::Server)
+        }
+    }
+
+    /// クライアントの生存確認
+    pub fn ping() -> Self {
+        Self::new(MsgKind::Ping, 0, Peer::Client)
+    }
+
+    /// Ping に対するサーバの応答
+    pub fn pong() -> Self {
+        Self::new(MsgKind::Pong, 0, Peer::Server)
+    }
+
+    /// セッションの異常終了
+    pub fn rst(from: Peer) -> Self {
+        Self::new(MsgKind::Rst, 0, from)
+    }
+
+    /// --bidi のサーバが自分の no で送る Data
+    pub fn push(no: u32) -> Self {
+        Self::new(MsgKind::Push, no, Peer::Server)
+    }
+
+    /// --stat の問い合わせ (クライアント) と、その応答 (サーバ)
+    pub fn stat(from: Peer) -> Self {
+        Self::new(MsgKind::Stat, 0, from)
+    }
+
+    pub fn with_retry(mut self, retry: u32) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    pub fn with_session(mut self, session: u64) -> Self {
+        self.session = session;
+        self
+    }
+
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// 累積 ACK を載せる
+    pub fn with_ack(mut self, ack: u32) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    /// FIN に載せるクライアントのまとめ
+    pub fn with_stats(mut self, stats: FinStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// 送信されてから今までの片道遅延 (ミリ秒)。sent_at が入っていなければ None
+    ///
+    /// 送信側と受信側の時計のずれがそのまま誤差になる (負の値になることもある) ので目安に過ぎない。
+    /// 同じホスト上で動かす場合は時計が共通なので、テスト用途には十分な精度になる。
+    pub fn one_way_delay_ms(&self) -> Option<i64> {
+        if self.sent_at == 0 {
+            return None;
+        }
+        Some(unix_millis() as i64 - self.sent_at as i64)
+    }
+
+    /// ログ用に片道遅延を " one_way=3ms" の形にする (不明なら空文字列)
+    pub(crate) fn one_way_label(&self) -> String {
+        match self.one_way_delay_ms() {
+            Some(ms) => format!(" one_way={}ms", ms),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_no_skips_zero_at_wraparound() {
+        assert_eq!(next_no(1), 2);
+        assert_eq!(next_no(u32::MAX - 1), u32::MAX);
+        assert_eq!(next_no(u32::MAX), FIRST_NO);
+    }
+
+    #[test]
+    fn seq_add_wraps_near_max() {
+        assert_eq!(seq_add(u32::MAX - 1, 0), u32::MAX - 1);
+        assert_eq!(seq_add(u32::MAX - 1, 1), u32::MAX);
+        assert_eq!(seq_add(u32::MAX - 1, 2), 1);
+        assert_eq!(seq_add(u32::MAX - 1, 3), 2);
+
+        // 送った側が k 個進めた値と、受けた側が next_no を k 回たどった値が一致する
+        let mut no = u32::MAX - 2;
+        for k in 1..=5 {
+            no = next_no(no);
+            assert_eq!(no, seq_add(u32::MAX - 2, k));
+            assert_ne!(no, 0);
+        }
+    }
+
+    #[test]
+    fn seq_diff_counts_steps_across_max() {
+        for (from, k) in [
+            (1, 0),
+            (5, 3),
+            (u32::MAX - 4, 4),
+            (u32::MAX - 4, 5),
+            (u32::MAX, 9),
+        ] {
+            assert_eq!(seq_diff(from, seq_add(from, k)), k);
+        }
+        // 0 はどの Data の no とも重ならないほど遠い
+        assert_eq!(seq_diff(1, 0), u32::MAX - 1);
+    }
+
+    #[test]
+    fn session_defaults_to_zero_for_old_peers() {
+        let msg: Message = serde_json::from_str(r#"{"no":3,"retry":0,"from":"client"}"#).unwrap();
+        assert_eq!(msg.session, 0);
+        assert!(msg.stats.is_none());
+        assert!(matches!(msg.kind, MsgKind::Data));
+        assert_eq!(msg.from, Peer::Client);
+    }
+
+    #[test]
+    fn constructors_set_kind_no_and_from() {
+        let fin = Message::fin(Peer::Client)
+            .with_session(9)
+            .with_stats(FinStats { sent: 3, acked: 2 });
+        assert_eq!(
+            (fin.kind, fin.no, fin.from),
+            (MsgKind::Fin, FIN_NO, Peer::Client)
+        );
+        assert_eq!(fin.session, 9);
+        assert_eq!(fin.stats, Some(FinStats { sent: 3, acked: 2 }));
+
+        let nack = Message::nack(vec![2, 5]);
+        assert_eq!((nack.kind, nack.from), (MsgKind::Nack, Peer::Server));
+        assert_eq!(nack.missing, vec![2, 5]);
+
+        let data = Message::data(4, Peer::Client)
+            .with_retry(1)
+            .with_payload(vec![1]);
+        assert_eq!((data.kind, data.no, data.retry), (MsgKind::Data, 4, 1));
+        assert_eq!(data.payload, vec![1]);
+        assert!(data.sent_at > 0 && data.crc.is_none());
+    }
+
+    #[test]
+    fn unknown_peer_is_rejected() {
+        let result = serde_json::from_str::<Message>(r#"{"no":3,"retry":0,"from":"sever"}"#);
+        assert!(result.is_err());
+    }
+
+    const ALL_KINDS: [MsgKind; 10] = [
+        MsgKind::Syn,
+        MsgKind::Data,
+        MsgKind::Fin,
+        MsgKind::Nack,
+        MsgKind::Ping,
+        MsgKind::Pong,
+        MsgKind::Rst,
+        MsgKind::Ack,
+        MsgKind::Push,
+        MsgKind::Stat,
+    ];
+
+    /// ワイルドカードを使わないので、MsgKind を足すとここがコンパイルエラーになり ALL_KINDS の更新漏れに気づける
+    fn kind_index(kind: MsgKind) -> usize {
+        match kind {
+            MsgKind::Syn => 0,
+            MsgKind::Data => 1,
+            MsgKind::Fin => 2,
+            MsgKind::Nack => 3,
+            MsgKind::Ping => 4,
+            MsgKind::Pong => 5,
+            MsgKind::Rst => 6,
+            MsgKind::Ack => 7,
+            MsgKind::Push => 8,
+            MsgKind::Stat => 9,
+        }
+    }
+
+    #[test]
+    fn all_kinds_round_trip_through_serde() {
+        for (i, &kind) in ALL_KINDS.iter().enumerate() {
+            assert_eq!(kind_index(kind), i);
+
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(serde_json::from_str::<MsgKind>(&json).unwrap(), kind);
+
+            let bytes = bincode::serialize(&kind).unwrap();
+            assert_eq!(bincode::deserialize::<MsgKind>(&bytes).unwrap(), kind);
+        }
+    }
+}
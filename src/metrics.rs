@@ -0,0 +1,86 @@
+use std::ops::AddAssign;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// 送受信の回数とバイト数のカウンタ。run_client / run_server が終了時に返す
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metrics {
+    /// 送信したデータグラムの数 (再送や制御メッセージも含む)
+    pub sent: u64,
+    /// 受信したデータグラムの数 (パースできなかったものも含む)
+    pub received: u64,
+    /// Data を再送した回数
+    pub retransmits: u64,
+    /// 応答待ちがタイムアウトした回数
+    pub timeouts: u64,
+    /// 受信済みの no をもう一度受信した回数
+    pub duplicates: u64,
+    /// 応答待ちのどの no とも合わない、想定外の応答を受信した回数
+    pub unexpected: u64,
+    /// サーバに届いたと確かめられた no の数 (個別の応答と累積 ACK の両方。クライアントだけが数える)
+    #[serde(default)]
+    pub acked: u64,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    /// RTT を測れた応答の数 (クライアントだけが測る)
+    pub rtt_samples: u64,
+    /// 測った RTT の合計 (マイクロ秒)
+    pub rtt_total_us: u64,
+}
+
+impl Metrics {
+    pub(crate) fn on_send(&mut self, bytes: usize) {
+        self.sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    pub(crate) fn on_recv(&mut self, bytes: usize) {
+        self.received += 1;
+        self.bytes_recv += bytes as u64;
+    }
+
+    pub(crate) fn on_rtt(&mut self, rtt: Duration) {
+        self.rtt_samples += 1;
+        self.rtt_total_us += rtt.as_micros() as u64;
+    }
+
+    /// RTT の平均 (測っていなければ None)
+    pub fn rtt_avg(&self) -> Option<Duration> {
+        (self.rtt_samples > 0).then(|| Duration::from_micros(self.rtt_total_us / self.rtt_samples))
+    }
+
+    /// 終了時にカウンタを 1 行で出す
+    pub(crate) fn log(&self, label: &str) {
+        info!(
+            "[{}] metrics: sent={}, received={}, retransmits={}, timeouts={}, duplicates={}, unexpected={}, acked={}, bytes_sent={}, bytes_recv={}",
+            label,
+            self.sent,
+            self.received,
+            self.retransmits,
+            self.timeouts,
+            self.duplicates,
+            self.unexpected,
+            self.acked,
+            self.bytes_sent,
+            self.bytes_recv
+        );
+    }
+}
+
+impl AddAssign for Metrics {
+    fn add_assign(&mut self, other: Self) {
+        self.sent += other.sent;
+        self.received += other.received;
+        self.retransmits += other.retransmits;
+        self.timeouts += other.timeouts;
+        self.duplicates += other.duplicates;
+        self.unexpected += other.unexpected;
+        self.acked += other.acked;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_recv += other.bytes_recv;
+        self.rtt_samples += other.rtt_samples;
+        self.rtt_total_us += other.rtt_total_us;
+    }
+}
@@ -0,0 +1,82 @@
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::metrics::Metrics;
+
+/// 進捗バーの見た目 (応答済みの数 / 全体の数と、status の文字列)
+const TEMPLATE: &str = "{elapsed_precise} [{bar:40}] {pos}/{len} {msg}";
+
+/// --progress でクライアントが出す進捗バー。出さないときは何もしない
+pub(crate) struct Progress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    /// enabled でなければ何も描かない (config.shows_progress() を渡す)
+    pub(crate) fn new(enabled: bool, count: u32) -> Self {
+        let bar = enabled.then(|| {
+            let bar =
+                ProgressBar::with_draw_target(Some(u64::from(count)), ProgressDrawTarget::stderr());
+            bar.set_style(
+                ProgressStyle::with_template(TEMPLATE)
+                    .expect("TEMPLATE は正しい書式")
+                    .progress_chars("=> "),
+            );
+            bar
+        });
+        Self { bar }
+    }
+
+    /// 応答済みの数 done と、まとめと同じカウンタから求めた再送率を描き直す
+    pub(crate) fn update(&self, done: u32, metrics: &Metrics) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(u64::from(done));
+            bar.set_message(status(metrics));
+        }
+    }
+
+    /// バーを最後の状態のまま残して閉じる (この後のまとめのログはバーの下に出る)
+    pub(crate) fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.abandon();
+        }
+    }
+}
+
+/// 進捗バーの右に出す、再送率とタイムアウトの回数
+fn status(metrics: &Metrics) -> String {
+    let rate = if metrics.sent == 0 {
+        0.0
+    } else {
+        metrics.retransmits as f64 * 100.0 / metrics.sent as f64
+    };
+    format!(
+        "retry={:.1}% ({}) timeouts={}",
+        rate, metrics.retransmits, metrics.timeouts
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_shows_retry_rate_of_sent() {
+        assert_eq!(status(&Metrics::default()), "retry=0.0% (0) timeouts=0");
+
+        let metrics = Metrics {
+            sent: 200,
+            retransmits: 5,
+            timeouts: 3,
+            ..Metrics::default()
+        };
+        assert_eq!(status(&metrics), "retry=2.5% (5) timeouts=3");
+    }
+
+    #[test]
+    fn disabled_progress_draws_nothing() {
+        let progress = Progress::new(false, 10);
+        assert!(progress.bar.is_none());
+        progress.update(5, &Metrics::default());
+        progress.finish();
+    }
+}
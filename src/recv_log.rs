@@ -0,0 +1,827 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::PACKET;
+use crate::message::FIRST_NO;
+use crate::metrics::Metrics;
+
+/// RecvLog に記録できる no の型 (いまの Message は u32。長く続くストリーム用に u64 も使える)
+pub trait SeqNo: Copy + Ord + fmt::Display + Serialize + DeserializeOwned {
+    /// 何も受信していないときの min / max
+    const ZERO: Self;
+    /// 最初の no (FIRST_NO)
+    const FIRST: Self;
+
+    /// first から数えて、first より小さい no を「最大値を過ぎて FIRST に戻った後」とみなす距離の上限 (型の半分)
+    const HALF: u64;
+
+    /// 次の no。型の最大値の次は 0 を飛ばして FIRST に戻る (seq_add と同じ)
+    fn seq_next(self) -> Self;
+
+    /// 前の no (seq_next の逆。FIRST の前は型の最大値)
+    fn seq_prev(self) -> Self;
+
+    /// from から seq_next を何回たどると self になるか (seq_diff と同じ)
+    fn seq_diff_from(self, from: Self) -> u64;
+
+    /// 前の no (0 の前は 0)
+    fn saturating_prev(self) -> Self;
+
+    /// 数や差を数えるときの u64
+    fn to_u64(self) -> u64;
+}
+
+macro_rules! impl_seq_no {
+    ($($t:ty),*) => {
+        $(
+            impl SeqNo for $t {
+                const ZERO: Self = 0;
+                const FIRST: Self = FIRST_NO as $t;
+                const HALF: u64 = (<$t>::MAX / 2) as u64;
+
+                fn seq_next(self) -> Self {
+                    if self == <$t>::MAX { Self::FIRST } else { self + 1 }
+                }
+
+                fn seq_prev(self) -> Self {
+                    if self <= Self::FIRST { <$t>::MAX } else { self - 1 }
+                }
+
+                fn seq_diff_from(self, from: Self) -> u64 {
+                    // FIRST は 1 なので、最大値から FIRST に戻る 1 歩と 0 を飛ばす分が打ち消し合う
+                    if self >= from {
+                        (self - from) as u64
+                    } else {
+                        (<$t>::MAX - from + self) as u64
+                    }
+                }
+
+                fn saturating_prev(self) -> Self {
+                    self.saturating_sub(1)
+                }
+
+                fn to_u64(self) -> u64 {
+                    u64::from(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_seq_no!(u32, u64);
+
+/// --json-summary で 1 行の JSON として出す、受信ログのまとめ
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub label: String,
+    /// 受信済みの no の数 (重複は数えない)
+    pub total: usize,
+    pub min: u64,
+    pub max: u64,
+    /// 1..=expected のうち受信できなかった no の数
+    pub missing: u64,
+    pub duplicates: u64,
+    pub loss_percent: f64,
+    pub elapsed_ms: u64,
+}
+
+/// 受信した no を記録して、「どこからどこまで受信済みか」を表示するための構造体
+///
+/// no の型は N (省略すると Message の no と同じ u32)。
+/// 抜けや連続区間は first から seq_next の順にたどるので、型の最大値を過ぎて FIRST に戻っても続けて数える。
+/// first より小さい no は、first から HALF 以内に入るときだけ FIRST に戻った後のものとみなし、
+/// それ以外 (前回の実行の残りなど) は first より前として抜けに数えない
+pub struct RecvLog<N: SeqNo = u32> {
+    label: String,
+    received: BTreeSet<N>,
+    /// 初めて受信した順に並べた no
+    arrival: Vec<N>,
+    /// 受信済みの no をもう一度受信した回数
+    duplicates: u64,
+    /// 受信するはずの no の最大値 (不明なら受信済みの最大値で代用する)
+    expected: Option<N>,
+    /// 受信するはずの最初の no。これより前の no は抜けとして数えない
+    first: N,
+    /// record の何回ごとにまとめを出すか
+    summary_every: u32,
+    /// record を呼んだ回数 (重複も数える)
+    records: u64,
+}
+
+impl<N: SeqNo> Default for RecvLog<N> {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl<N: SeqNo> RecvLog<N> {
+    pub fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            received: BTreeSet::new(),
+            arrival: Vec::new(),
+            duplicates: 0,
+            expected: None,
+            first: N::FIRST,
+            summary_every: 1,
+            records: 0,
+        }
+    }
+
+    /// save で書き出した受信済みの no を読み込む。ファイルがなければ空の受信ログを返す
+    pub fn load(path: &Path, label: &str) -> io::Result<Self> {
+        let log = Self::new(label);
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(log),
+            Err(e) => return Err(e),
+        };
+        let received = serde_json::from_reader(BufReader::new(file))?;
+        Ok(log.with_received(received))
+    }
+
+    /// save で書き出した受信済みの no から続ける (前回の受信順は残していないので、no 順に受信したことにする)
+    pub(crate) fn with_received(mut self, received: BTreeSet<N>) -> Self {
+        self.arrival = received.iter().copied().collect();
+        self.received = received;
+        self
+    }
+
+    /// 受信済みの no (save で書き出すもの)
+    pub(crate) fn received(&self) -> &BTreeSet<N> {
+        &self.received
+    }
+
+    /// ログの接頭辞を付け替える (load した受信ログをセッションに引き継ぐとき用)
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    /// first から受信するはずだと分かっている場合に、抜けや損失率をそこから数える (0 は FIRST_NO とみなす)
+    pub fn with_first(mut self, first: N) -> Self {
+        self.first = first.max(N::FIRST);
+        self
+    }
+
+    /// first..=expected を受信するはずだと分かっている場合に、損失率の分母として使う
+    pub fn with_expected(mut self, expected: N) -> Self {
+        self.expected = Some(expected);
+        self
+    }
+
+    /// record の every 回ごとにだけまとめを出す (0 は 1 とみなす)。まとめは受信済みの no の数に比例して重い
+    pub fn with_summary_every(mut self, every: u32) -> Self {
+        self.summary_every = every.max(1);
+        self
+    }
+
+    pub fn record(&mut self, no: N) {
+        if self.received.insert(no) {
+            self.arrival.push(no);
+        } else {
+            self.duplicates += 1;
+        }
+
+        self.records += 1;
+        if self.records.is_multiple_of(u64::from(self.summary_every)) {
+            info!(target: PACKET, "{}", self.summary_line());
+        }
+    }
+
+    /// other の受信を自分のものとして足す (--streams で区間ごとのログを 1 つにまとめる)。
+    /// 両方で受信していた no は重複として数える。ログは出さない
+    pub fn merge(&mut self, other: &Self) {
+        for &no in &other.arrival {
+            if self.received.insert(no) {
+                self.arrival.push(no);
+            } else {
+                self.duplicates += 1;
+            }
+        }
+        self.duplicates += other.duplicates;
+    }
+
+    /// セッション終了時などに、受信ログのまとめを 1 行出す
+    pub fn log_summary(&self) {
+        info!("{}", self.summary_line());
+    }
+
+    /// 隣り合う受信済みの no の差ごとの出現回数 (1 は連続、2 は 1 個抜け)。損失が単発か固まっているかを見る
+    ///
+    /// first から seq_next の順にたどるので、u32::MAX の次の 1 との差も 1 になる。
+    /// 差のキーが u32 でなく u64 なのは、RecvLog<u64> では差が u32 に収まらないことがあるため
+    pub fn gap_histogram(&self) -> BTreeMap<u64, u64> {
+        let mut hist = BTreeMap::new();
+        let mut prev = None;
+        for n in self.in_seq_order() {
+            if let Some(p) = prev {
+                *hist.entry(n.seq_diff_from(p)).or_insert(0) += 1;
+            }
+            prev = Some(n);
+        }
+        hist
+    }
+
+    /// gap_histogram を "gap×回数" の並びで 1 行出す
+    pub fn log_gap_histogram(&self) {
+        let hist: Vec<String> = self
+            .gap_histogram()
+            .iter()
+            .map(|(gap, count)| format!("{}x{}", gap, count))
+            .collect();
+        info!("[{}] gap histogram: {}", self.label, hist.join(", "));
+    }
+
+    /// 受信ログのまとめを、かかった時間と合わせて返す
+    pub fn summary(&self, elapsed: Duration) -> Summary {
+        let expected = self.expected.unwrap_or(self.last());
+        let received = self.count_in(expected);
+        Summary {
+            label: self.label.clone(),
+            total: self.total(),
+            min: self.min().to_u64(),
+            max: self.max().to_u64(),
+            missing: self.span(expected) - received,
+            duplicates: self.duplicates,
+            loss_percent: self.loss_percent(expected),
+            elapsed_ms: elapsed.as_millis() as u64,
+        }
+    }
+
+    /// クライアントの summary。total / missing / loss_percent は受信した応答ではなく metrics.acked から出す
+    pub fn acked_summary(&self, metrics: &Metrics, elapsed: Duration) -> Summary {
+        let span = self.span(self.expected.unwrap_or(self.last()));
+        let missing = span.saturating_sub(metrics.acked);
+        Summary {
+            total: metrics.acked as usize,
+            missing,
+            loss_percent: missing_percent(missing, span),
+            ..self.summary(elapsed)
+        }
+    }
+
+    /// シェルで拾いやすい、空白区切りの key=value の 1 行の結果
+    /// (例: result=ok count=100 loss=0.0% dups=3 retrans=5 timeouts=5 rtt_avg=12.0ms dur=1.3s)
+    ///
+    /// result は first..=expected をすべて受信できていれば ok、抜けがあれば incomplete。
+    /// RTT を測っていなければ rtt_avg=-
+    pub fn report_line(&self, metrics: &Metrics, elapsed: Duration) -> String {
+        let expected = self.expected.unwrap_or(self.last());
+        self.format_report(self.count_in(expected), metrics, elapsed)
+    }
+
+    /// クライアントの report_line。count / loss / result は受信した応答ではなく metrics.acked から出す
+    /// (応答が落ちても累積 ACK で届いたとわかった no は、受信ログになくても ok に数える)
+    pub fn acked_report_line(&self, metrics: &Metrics, elapsed: Duration) -> String {
+        self.format_report(metrics.acked, metrics, elapsed)
+    }
+
+    /// first..=expected のうち delivered 個が届いたとして report_line の 1 行を作る
+    fn format_report(&self, delivered: u64, metrics: &Metrics, elapsed: Duration) -> String {
+        let span = self.span(self.expected.unwrap_or(self.last()));
+        let missing = span.saturating_sub(delivered);
+        let rtt_avg = match metrics.rtt_avg() {
+            Some(rtt) => format!("{:.1}ms", rtt.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        format!(
+            "result={} count={} loss={:.1}% dups={} retrans={} timeouts={} rtt_avg={} dur={:.1}s",
+            if missing == 0 { "ok" } else { "incomplete" },
+            delivered,
+            missing_percent(missing, span),
+            self.duplicates,
+            metrics.retransmits,
+            metrics.timeouts,
+            rtt_avg,
+            elapsed.as_secs_f64()
+        )
+    }
+
+    fn summary_line(&self) -> String {
+        let summary = self.build_ranges_summary();
+        let min = self.min();
+        let max = self.max();
+        let total = self.total();
+        let loss = self.loss_percent(self.expected.unwrap_or(self.last()));
+
+        format!(
+            "[{}] 受信ログ: min={}, max={}, total={}, dups={}, loss={:.1}%, ranges=[{}]",
+            self.label, min, max, total, self.duplicates, loss, summary
+        )
+    }
+
+    /// 受信順に "no,index" の CSV を書き出す (index は 0 から始まる受信順の通し番号)
+    pub fn export_csv(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "no,index")?;
+        for (index, no) in self.arrival.iter().enumerate() {
+            writeln!(w, "{},{}", no, index)?;
+        }
+        w.flush()
+    }
+
+    /// 受信済みの no を JSON の配列で書き出す (save_json)
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_json(path, &self.received)
+    }
+
+    /// 抜けや損失率を数え始める no (with_first)
+    pub fn first(&self) -> N {
+        self.first
+    }
+
+    /// 受信済みの no を重複して受信した回数
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// 受信済みの no の数 (重複は数えない)
+    pub fn total(&self) -> usize {
+        self.received.len()
+    }
+
+    /// 受信済みの最小の no (まだ何も受信していなければ 0)
+    pub fn min(&self) -> N {
+        self.received.first().copied().unwrap_or(N::ZERO)
+    }
+
+    /// 受信済みの最大の no (まだ何も受信していなければ 0)
+    pub fn max(&self) -> N {
+        self.received.last().copied().unwrap_or(N::ZERO)
+    }
+
+    /// first から seq_next の順にたどって最後に並ぶ受信済みの no (まだ何も受信していなければ 0)。
+    /// FIRST に戻った後の no を受信していれば max より小さい
+    pub fn last(&self) -> N {
+        self.wrapped()
+            .last()
+            .or_else(|| self.received.last().copied())
+            .unwrap_or(N::ZERO)
+    }
+
+    /// first..=no (seq_next の順) をすべて受信済みであるような最後の no (first を受信していなければ first - 1)
+    pub fn contiguous_prefix_max(&self) -> N {
+        let mut max = self.first.saturating_prev();
+        let mut expected = self.first;
+        for n in self.in_seq_order() {
+            if n != expected {
+                break;
+            }
+            max = n;
+            expected = n.seq_next();
+        }
+        max
+    }
+
+    /// first から last() までのうち、まだ受信していない no を seq_next の順に最大 limit 個返す
+    pub fn missing(&self, limit: usize) -> Vec<N> {
+        let mut missing = Vec::new();
+        let mut expected = self.first;
+
+        for n in self.in_seq_order() {
+            while expected != n {
+                if missing.len() >= limit {
+                    return missing;
+                }
+                missing.push(expected);
+                expected = expected.seq_next();
+            }
+            expected = n.seq_next();
+        }
+
+        missing
+    }
+
+    /// 受信済みの no のうち first 以降のものを、first から seq_next の順に並べる
+    pub(crate) fn in_seq_order(&self) -> impl Iterator<Item = N> + '_ {
+        self.received
+            .range(self.first..)
+            .copied()
+            .chain(self.wrapped())
+    }
+
+    /// first より小さい受信済みの no のうち、型の最大値を過ぎて FIRST に戻った後のもの
+    fn wrapped(&self) -> impl Iterator<Item = N> + '_ {
+        let first = self.first;
+        self.received
+            .range(N::FIRST..first)
+            .copied()
+            .take_while(move |n| n.seq_diff_from(first) <= N::HALF)
+    }
+
+    /// first から seq_next の順にたどって no は何番目か (first より前なら None)
+    fn offset(&self, no: N) -> Option<u64> {
+        if no < N::FIRST {
+            return None;
+        }
+        let offset = no.seq_diff_from(self.first);
+        (no >= self.first || offset <= N::HALF).then_some(offset)
+    }
+
+    /// first..=expected (seq_next の順) のうち受信できなかった割合 (%)。expected が first より前なら 0.0
+    pub fn loss_percent(&self, expected: N) -> f64 {
+        let span = self.span(expected);
+        if span == 0 {
+            return 0.0;
+        }
+        let received = self.count_in(expected) as f64;
+        let span = span as f64;
+        (span - received) / span * 100.0
+    }
+
+    /// first..=expected (seq_next の順) の no の数
+    fn span(&self, expected: N) -> u64 {
+        self.offset(expected).map_or(0, |offset| offset + 1)
+    }
+
+    /// first..=expected (seq_next の順) のうち受信済みの no の数
+    fn count_in(&self, expected: N) -> u64 {
+        let Some(limit) = self.offset(expected) else {
+            return 0;
+        };
+        self.in_seq_order()
+            .take_while(|&n| n.seq_diff_from(self.first) <= limit)
+            .count() as u64
+    }
+
+    /// 受信済みの no を、連続区間ごとに "1-5, 7-10, 12" のような文字列にする
+    ///
+    /// first より前の no を先に並べ、続けて first から seq_next の順に並べる
+    /// (FIRST に戻った後は "4294967290-4294967295, 1-4" のように区間を分ける)
+    pub fn build_ranges_summary(&self) -> String {
+        let mut ranges = Vec::new();
+        let mut start: Option<N> = None;
+        let mut prev: Option<N> = None;
+
+        let before_first = self
+            .received
+            .range(..self.first)
+            .copied()
+            .filter(|&n| self.offset(n).is_none());
+        for n in before_first.chain(self.in_seq_order()) {
+            match (start, prev) {
+                (None, _) => {
+                    start = Some(n);
+                    prev = Some(n);
+                }
+                (Some(s), Some(p)) => {
+                    if n > p && p.seq_next() == n {
+                        // 連続
+                        prev = Some(n);
+                    } else {
+                        // 途切れたのでひと区間確定
+                        if s == p {
+                            ranges.push(format!("{}", s));
+                        } else {
+                            ranges.push(format!("{}-{}", s, p));
+                        }
+                        start = Some(n);
+                        prev = Some(n);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let (Some(s), Some(p)) = (start, prev) {
+            if s == p {
+                ranges.push(format!("{}", s));
+            } else {
+                ranges.push(format!("{}-{}", s, p));
+            }
+        }
+
+        ranges.join(", ")
+    }
+
+    /// first..=up_to (seq_next の順) のうち未受信の no を、"4, 6, 10-11" のような文字列にする (抜けがなければ空文字列)
+    pub fn missing_ranges(&self, up_to: N) -> String {
+        let Some(limit) = self.offset(up_to) else {
+            return String::new();
+        };
+        let mut ranges = Vec::new();
+        let mut push = |s: N, e: N| {
+            if s == e {
+                ranges.push(format!("{}", s));
+            } else {
+                ranges.push(format!("{}-{}", s, e));
+            }
+        };
+
+        // up_to まで受信済みなら、その次は数えない (None)
+        let mut expected = Some(self.first);
+        for n in self
+            .in_seq_order()
+            .take_while(|&n| n.seq_diff_from(self.first) <= limit)
+        {
+            if let Some(e) = expected
+                && n != e
+            {
+                push(e, n.seq_prev());
+            }
+            expected = (n != up_to).then(|| n.seq_next());
+        }
+        if let Some(e) = expected {
+            push(e, up_to);
+        }
+
+        ranges.join(", ")
+    }
+}
+
+/// value を JSON で path に書き出す。途中で落ちても壊れないように、一時ファイルに書いてから置き換える
+///
+/// 一時ファイルは path に ".tmp" を足した名前 (拡張子を付け替えると、path や隣のファイルと重なることがある)
+pub(crate) fn save_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut w, value)?;
+    w.flush()?;
+    drop(w);
+    fs::rename(&tmp, path)
+}
+
+/// span 個のうち missing 個が届かなかった割合 (%)。span が 0 なら 0.0
+fn missing_percent(missing: u64, span: u64) -> f64 {
+    if span == 0 {
+        return 0.0;
+    }
+    missing as f64 * 100.0 / span as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with(nos: &[u32]) -> RecvLog {
+        let mut log = RecvLog::new("TEST");
+        log.received.extend(nos.iter().copied());
+        log
+    }
+
+    #[test]
+    fn ranges_summary_empty() {
+        assert_eq!(log_with(&[]).build_ranges_summary(), "");
+    }
+
+    #[test]
+    fn ranges_summary_single() {
+        assert_eq!(log_with(&[5]).build_ranges_summary(), "5");
+    }
+
+    #[test]
+    fn ranges_summary_disjoint_singletons() {
+        assert_eq!(log_with(&[2, 4]).build_ranges_summary(), "2, 4");
+    }
+
+    #[test]
+    fn ranges_summary_contiguous() {
+        assert_eq!(log_with(&[1, 2, 3, 4, 5]).build_ranges_summary(), "1-5");
+    }
+
+    #[test]
+    fn ranges_summary_mixed() {
+        let log = log_with(&[1, 2, 3, 5, 7, 8, 9]);
+        assert_eq!(log.build_ranges_summary(), "1-3, 5, 7-9");
+    }
+
+    #[test]
+    fn missing_ranges_gaps() {
+        let log = log_with(&[1, 2, 3, 5, 7, 8, 9, 12]);
+        assert_eq!(log.missing_ranges(12), "4, 6, 10-11");
+    }
+
+    #[test]
+    fn missing_ranges_tail_and_head() {
+        let log = log_with(&[3, 4]);
+        assert_eq!(log.missing_ranges(6), "1-2, 5-6");
+        assert_eq!(log_with(&[]).missing_ranges(3), "1-3");
+    }
+
+    #[test]
+    fn missing_ranges_none_missing() {
+        let log = log_with(&[1, 2, 3]);
+        assert_eq!(log.missing_ranges(3), "");
+        assert_eq!(log.missing_ranges(2), "");
+        assert_eq!(log_with(&[]).missing_ranges(0), "");
+    }
+
+    #[test]
+    fn loss_percent_counts_only_expected_range() {
+        let log = log_with(&[1, 2, 4, 20]);
+        assert_eq!(log.loss_percent(4), 25.0);
+        assert_eq!(log.loss_percent(0), 0.0);
+        assert_eq!(log_with(&[]).loss_percent(10), 100.0);
+    }
+
+    #[test]
+    fn ranges_summary_via_record() {
+        let mut log: RecvLog = RecvLog::new("TEST");
+        for no in [3, 1, 2, 2, 9] {
+            log.record(no);
+        }
+        assert_eq!(log.build_ranges_summary(), "1-3, 9");
+        assert_eq!(log.duplicates(), 1);
+    }
+
+    #[test]
+    fn summary_round_trips_through_json() {
+        let mut log: RecvLog = RecvLog::new("TEST").with_expected(6);
+        for no in [1, 2, 2, 4, 5] {
+            log.record(no);
+        }
+        let summary = log.summary(Duration::from_millis(1500));
+        assert_eq!(summary.total, 4);
+        assert_eq!(summary.min, 1);
+        assert_eq!(summary.max, 5);
+        assert_eq!(summary.missing, 2);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.elapsed_ms, 1500);
+
+        let json = serde_json::to_string(&summary).unwrap();
+        let parsed: Summary = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, summary);
+    }
+
+    #[test]
+    fn gap_histogram_counts_each_gap() {
+        let log = log_with(&[1, 2, 3, 5, 6, 10]);
+        let hist: Vec<(u64, u64)> = log.gap_histogram().into_iter().collect();
+        assert_eq!(hist, [(1, 3), (2, 1), (4, 1)]);
+        assert!(log_with(&[7]).gap_histogram().is_empty());
+
+        // u32::MAX の次は 1 なので、そこも連続に数える
+        let log = log_with(&[u32::MAX - 1, u32::MAX, 1, 3]).with_first(u32::MAX - 1);
+        let hist: Vec<(u64, u64)> = log.gap_histogram().into_iter().collect();
+        assert_eq!(hist, [(1, 2), (2, 1)]);
+    }
+
+    #[test]
+    fn first_excludes_earlier_nos() {
+        let mut log = log_with(&[100, 101, 103]).with_first(100);
+        log.expected = Some(104);
+        assert_eq!(log.missing(10), [102]);
+        assert_eq!(log.missing_ranges(104), "102, 104");
+        assert_eq!(log.contiguous_prefix_max(), 101);
+        assert_eq!(log.loss_percent(104), 40.0);
+        assert_eq!(log.summary(Duration::ZERO).missing, 2);
+        assert_eq!(log_with(&[]).with_first(100).contiguous_prefix_max(), 99);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("udp_tool_state_{}.json", std::process::id()));
+        log_with(&[1, 2, 5, 9]).save(&path).unwrap();
+        let log: RecvLog = RecvLog::load(&path, "TEST").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(log.build_ranges_summary(), "1-2, 5, 9");
+        assert_eq!(log.missing(10), [3, 4, 6, 7, 8]);
+
+        // ファイルがなければ空の受信ログ
+        let log: RecvLog = RecvLog::load(&path, "TEST").unwrap();
+        assert_eq!(log.total(), 0);
+
+        // 拡張子だけ違う隣のファイルは上書きしない
+        let neighbor = path.with_extension("tmp");
+        fs::write(&neighbor, "keep").unwrap();
+        log_with(&[3]).save(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&neighbor).unwrap(), "keep");
+        fs::remove_file(&neighbor).unwrap();
+    }
+
+    #[test]
+    fn report_line_is_one_line_of_key_values() {
+        let mut log: RecvLog = RecvLog::new("TEST").with_expected(4);
+        for no in [1, 2, 2, 3, 4] {
+            log.record(no);
+        }
+        let metrics = Metrics {
+            retransmits: 5,
+            timeouts: 5,
+            rtt_samples: 2,
+            rtt_total_us: 24_000,
+            ..Metrics::default()
+        };
+        assert_eq!(
+            log.report_line(&metrics, Duration::from_millis(1300)),
+            "result=ok count=4 loss=0.0% dups=1 retrans=5 timeouts=5 rtt_avg=12.0ms dur=1.3s"
+        );
+
+        let log = log.with_expected(8);
+        assert_eq!(
+            log.report_line(&Metrics::default(), Duration::ZERO),
+            "result=incomplete count=4 loss=50.0% dups=1 retrans=0 timeouts=0 rtt_avg=- dur=0.0s"
+        );
+    }
+
+    #[test]
+    fn acked_report_line_counts_acked_nos() {
+        // 応答は 2 つしか届いていないが、累積 ACK で 4 つとも届いたとわかっている
+        let mut log: RecvLog = RecvLog::new("TEST").with_expected(4);
+        for no in [1, 4] {
+            log.record(no);
+        }
+        let metrics = Metrics {
+            acked: 4,
+            ..Metrics::default()
+        };
+        assert_eq!(
+            log.acked_report_line(&metrics, Duration::ZERO),
+            "result=ok count=4 loss=0.0% dups=0 retrans=0 timeouts=0 rtt_avg=- dur=0.0s"
+        );
+
+        let metrics = Metrics {
+            acked: 3,
+            ..Metrics::default()
+        };
+        assert_eq!(
+            log.acked_report_line(&metrics, Duration::ZERO),
+            "result=incomplete count=3 loss=25.0% dups=0 retrans=0 timeouts=0 rtt_avg=- dur=0.0s"
+        );
+    }
+
+    #[test]
+    fn merge_combines_disjoint_logs() {
+        let mut a: RecvLog = RecvLog::new("A").with_expected(6);
+        for no in [1, 2, 2, 3] {
+            a.record(no);
+        }
+        let mut b: RecvLog = RecvLog::new("B");
+        for no in [5, 6, 3] {
+            b.record(no);
+        }
+        a.merge(&b);
+        assert_eq!(a.total(), 5);
+        assert_eq!(a.duplicates(), 2);
+        assert_eq!(a.build_ranges_summary(), "1-3, 5-6");
+        assert_eq!(a.missing(10), [4]);
+    }
+
+    #[test]
+    fn u64_nos_past_u32_max() {
+        let big = u64::from(u32::MAX);
+        let mut log = RecvLog::<u64>::new("TEST").with_first(big - 1);
+        for no in [big - 1, big, big + 1, big + 3] {
+            log.record(no);
+        }
+        assert_eq!(
+            log.build_ranges_summary(),
+            "4294967294-4294967296, 4294967298"
+        );
+        assert_eq!(log.missing(10), [big + 2]);
+        assert_eq!(log.missing_ranges(big + 4), "4294967297, 4294967299");
+        assert_eq!(log.contiguous_prefix_max(), big + 1);
+        assert_eq!(log.summary(Duration::ZERO).max, big + 3);
+
+        // 型の最大値で止まっていれば、FIRST に戻った先は抜けに数えない
+        let log = log_with(&[u32::MAX - 1, u32::MAX]).with_first(u32::MAX - 1);
+        assert_eq!(log.contiguous_prefix_max(), u32::MAX);
+        assert_eq!(log.missing_ranges(u32::MAX), "");
+    }
+
+    #[test]
+    fn u32_nos_wrap_past_max_to_first() {
+        let first = u32::MAX - 5;
+        let mut log = log_with(&[first, first + 1, first + 2, first + 3, first + 4, u32::MAX])
+            .with_first(first)
+            .with_expected(4);
+        assert_eq!(log.contiguous_prefix_max(), u32::MAX);
+        assert_eq!(log.missing_ranges(4), "1-4");
+
+        for no in [1, 2, 4] {
+            log.record(no);
+        }
+        assert_eq!(log.contiguous_prefix_max(), 2);
+        assert_eq!(log.last(), 4);
+        assert_eq!(log.missing(10), [3]);
+        assert_eq!(log.missing_ranges(4), "3");
+        assert_eq!(log.loss_percent(4), 10.0);
+        assert_eq!(log.build_ranges_summary(), "4294967290-4294967295, 1-2, 4");
+
+        // first から遠い小さい no (前回の実行の残りなど) は、FIRST に戻った後とはみなさない
+        let log = log_with(&[1, 2, 100, 101]).with_first(100);
+        assert_eq!(log.last(), 101);
+        assert!(log.missing(10).is_empty());
+        assert_eq!(log.build_ranges_summary(), "1-2, 100-101");
+    }
+
+    #[test]
+    fn contiguous_prefix_max_stops_at_first_gap() {
+        assert_eq!(RecvLog::<u32>::new("T").contiguous_prefix_max(), 0);
+        assert_eq!(log_with(&[2, 3, 4]).contiguous_prefix_max(), 0);
+        assert_eq!(log_with(&[1, 2, 3]).contiguous_prefix_max(), 3);
+        assert_eq!(log_with(&[1, 2, 4, 5]).contiguous_prefix_max(), 2);
+        assert_eq!(log_with(&[5, 1, 3, 2]).contiguous_prefix_max(), 3);
+    }
+}
@@ -0,0 +1,1252 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::future::poll_fn;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::task::Poll;
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use tracing::{error, info, warn};
+
+use crate::client::build_payload;
+use crate::codec::{Codec, DecodeError, Head};
+use crate::config::Config;
+use crate::error::CommError;
+use crate::message::{
+    FIN_NO, FIRST_NO, FinStats, Message, MsgKind, Peer, next_no, seq_add, seq_diff, unix_millis,
+};
+use crate::metrics::Metrics;
+use crate::recv_log::{RecvLog, save_json};
+use crate::sim::NetSim;
+use crate::{
+    HEX_DUMP_LEVEL, PACKET, RECV_BUF_SIZE, apply_dscp, log_hex_dump, log_throughput,
+    warn_if_truncated,
+};
+
+/// 1 つの NACK に載せる no の上限
+const MAX_NACK_LEN: usize = 64;
+
+/// 同じ応答をこの回数再送するたびに、片方向だけ通信が悪化している可能性を警告する
+const RESEND_WARN_EVERY: u32 = 10;
+
+/// 受信ログの抜けを NACK でクライアントに知らせる (抜けがなければ何もしない)
+async fn send_nack(
+    socket: &UdpSocket,
+    codec: Codec,
+    sim: &mut NetSim,
+    session: &SessionState,
+    addr: SocketAddr,
+    metrics: &mut Metrics,
+    label: &str,
+) -> Result<(), CommError> {
+    let missing = session.recv_log.missing(MAX_NACK_LEN);
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let nack = Message::nack(missing)
+        .with_label(label)
+        .with_session(session.session);
+    let data = codec.encode(&nack)?;
+    sim.send_to(socket, &data, addr, label, nack.no).await?;
+    metrics.on_send(data.len());
+    info!(target: PACKET, addr = %addr, "[{}] NACK 送信 to {}: {:?}", label, addr, nack.missing);
+    Ok(())
+}
+
+/// Stat の応答に載せる、セッション 1 つ分の様子 (--stat で JSON のまま出す)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStat {
+    pub addr: SocketAddr,
+    pub session: u64,
+    /// 受信済みの no の数 (重複は数えない)
+    pub received: usize,
+    pub duplicates: u64,
+    /// 受信済みの最大の no
+    pub max: u32,
+    pub bytes_received: u64,
+    /// 最後にこのクライアントから受信してからの時間 (ミリ秒)
+    pub last_seen_ms: u64,
+    /// セッションを作ってからの時間 (ミリ秒)
+    pub age_ms: u64,
+}
+
+/// クライアント 1 つ分のセッション状態
+struct SessionState {
+    /// クライアントが SYN (または最初の Data) で名乗ったセッション ID
+    session: u64,
+    /// クライアントから受信した no のログ
+    recv_log: RecvLog,
+    /// タイムアウト時に再送する、直前に送った応答
+    last_msg: Option<Message>,
+    /// 最後にこのクライアントから受信した時刻
+    last_seen: Instant,
+    /// セッションを作った時刻
+    started: Instant,
+    /// このクライアントから受信した Data のバイト数
+    bytes_received: u64,
+    /// --out を指定したときに書き出す、no ごとの payload
+    payloads: BTreeMap<u32, Vec<u8>>,
+    /// このクライアントが送ってきたソケットの番号 (応答はそのソケットから返す)
+    local: usize,
+    /// --bidi でこのクライアントに送った Push の数 (次の Push の no は FIRST_NO からこの数だけ先)
+    pushed: u32,
+    /// --bidi で送った Push のうち、クライアントから Ack が返ってきた no のログ
+    push_log: RecvLog,
+}
+
+impl SessionState {
+    /// prior があれば (--state-file から読み込んだ前回の進捗)、受信ログをそこから続ける
+    fn new(
+        addr: SocketAddr,
+        session: u64,
+        local: usize,
+        config: &Config,
+        prior: Option<RecvLog>,
+    ) -> Self {
+        Self {
+            session,
+            recv_log: prior
+                .unwrap_or_default()
+                .with_label(&format!("{}-RECV {}", config.server_label(), addr))
+                .with_summary_every(config.summary_every),
+            last_msg: None,
+            last_seen: Instant::now(),
+            started: Instant::now(),
+            bytes_received: 0,
+            payloads: BTreeMap::new(),
+            local,
+            pushed: 0,
+            push_log: RecvLog::new(&format!("{}-PUSH {}", config.server_label(), addr))
+                .with_summary_every(config.summary_every),
+        }
+    }
+
+    fn stat(&self, addr: SocketAddr) -> SessionStat {
+        SessionStat {
+            addr,
+            session: self.session,
+            received: self.recv_log.total(),
+            duplicates: self.recv_log.duplicates(),
+            max: self.recv_log.max(),
+            bytes_received: self.bytes_received,
+            last_seen_ms: self.last_seen.elapsed().as_millis() as u64,
+            age_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+
+    /// 受信ログのまとめとスループットを出す。json_summary なら JSON の 1 行も標準出力に出す
+    fn log_summary(&self, label: &str, json_summary: bool) -> Result<(), CommError> {
+        self.recv_log.log_summary();
+        log_throughput(
+            label,
+            self.recv_log.total() as u64,
+            self.bytes_received,
+            self.started.elapsed(),
+        );
+        if json_summary {
+            let summary = self.recv_log.summary(self.started.elapsed());
+            println!("{}", serde_json::to_string(&summary)?);
+        }
+        Ok(())
+    }
+
+    /// FIN に載っていたクライアントのまとめを、自分の受信ログと突き合わせてログに出す (食い違えば warn)
+    fn reconcile(&self, addr: SocketAddr, stats: FinStats, label: &str) {
+        let received = self.recv_log.total();
+        if received == stats.sent as usize {
+            info!(
+                addr = %addr,
+                acked = stats.acked,
+                "[{}] client claims sent {}, I received {}",
+                label,
+                stats.sent,
+                received
+            );
+        } else {
+            warn!(
+                addr = %addr,
+                acked = stats.acked,
+                "[{}] client claims sent {}, I received {}",
+                label,
+                stats.sent,
+                received
+            );
+        }
+    }
+
+    /// セッション終了時の後始末。まとめを出し、指定があれば CSV と受信した payload を書き出す
+    ///
+    /// 最後に report_line を標準出力に出す (retrans / timeouts は metrics の、サーバ全体の数)
+    fn finish(
+        &self,
+        addr: SocketAddr,
+        config: &Config,
+        metrics: &Metrics,
+    ) -> Result<(), CommError> {
+        self.log_summary(config.server_label(), config.json_summary)?;
+        if config.bidi {
+            info!(
+                addr = %addr,
+                "[{}] Push: 送信 {}, Ack 受信 {}",
+                config.server_label(),
+                self.pushed,
+                self.push_log.total()
+            );
+        }
+        if config.hist {
+            self.recv_log.log_gap_histogram();
+        }
+        if let Some(path) = &config.log_csv {
+            // 複数のクライアントや --once なしのときに前のセッションの CSV を上書きしないように、セッションごとに分ける
+            let path = session_csv_path(path, self.session);
+            self.recv_log.export_csv(&path)?;
+            info!(
+                addr = %addr,
+                "[{}] {} の受信ログを {} に書き出しました",
+                config.server_label(),
+                addr,
+                path.display()
+            );
+        }
+        if let Some(path) = &config.out {
+            if !self.recv_log.missing(1).is_empty() {
+                warn!(
+                    addr = %addr,
+                    "[{}] 欠けた no があるまま {} に書き出します",
+                    config.server_label(),
+                    path.display()
+                );
+            }
+            // 受信ログと同じく first から seq_next の順に並べる (u32::MAX の次は 1 のチャンク)
+            let data: Vec<u8> = self
+                .recv_log
+                .in_seq_order()
+                .filter_map(|no| self.payloads.get(&no))
+                .flatten()
+                .copied()
+                .collect();
+            fs::write(path, &data)?;
+            info!(
+                addr = %addr,
+                "[{}] {} バイトを {} に書き出しました",
+                config.server_label(),
+                data.len(),
+                path.display()
+            );
+        }
+        info!(
+            addr = %addr,
+            "[{}] セッションを終了します: {}",
+            config.server_label(),
+            addr
+        );
+        println!(
+            "{}",
+            self.recv_log.report_line(metrics, self.started.elapsed())
+        );
+        Ok(())
+    }
+}
+
+/// --log-csv のパスに、セッション ID を入れたファイル名を作る (例: recv.csv → recv-42.csv)
+fn session_csv_path(path: &Path, session: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, session, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, session),
+    };
+    path.with_file_name(name)
+}
+
+/// --state-file の中身。クライアントのアドレスごとの受信済みの no
+type SavedState = BTreeMap<SocketAddr, BTreeSet<u32>>;
+
+/// --state-file から前回の進捗を読み込む。ファイルがないか空なら、引き継ぐものはない
+fn load_state(path: &Path, label: &str) -> Result<HashMap<SocketAddr, RecvLog>, CommError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    if text.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let saved: SavedState = serde_json::from_str(&text)?;
+    Ok(saved
+        .into_iter()
+        .filter(|(_, received)| !received.is_empty())
+        .map(|(addr, received)| (addr, RecvLog::new(label).with_received(received)))
+        .collect())
+}
+
+/// ack_delay だけ遅らせて送る Data の応答
+struct DelayedSend {
+    due: Instant,
+    /// 送信に使うソケットの番号
+    local: usize,
+    addr: SocketAddr,
+    no: u32,
+    data: Vec<u8>,
+}
+
+/// どれかのソケットに届いたデータグラムを受信し、(ソケットの番号, バイト数, 送信元) を返す
+async fn recv_from_any(
+    sockets: &[UdpSocket],
+    buf: &mut [u8],
+) -> io::Result<(usize, usize, SocketAddr)> {
+    poll_fn(|cx| {
+        for (i, socket) in sockets.iter().enumerate() {
+            let mut read_buf = ReadBuf::new(buf);
+            if let Poll::Ready(r) = socket.poll_recv_from(cx, &mut read_buf) {
+                return Poll::Ready(r.map(|addr| (i, read_buf.filled().len(), addr)));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// --echo のサーバ処理
+///
+/// セッションも再送も扱わず、受信したデータグラムをログに出してそのまま送り返す
+async fn run_echo(
+    sockets: &[UdpSocket],
+    sim: &mut NetSim,
+    metrics: &mut Metrics,
+    config: &Config,
+    on_message: &mut impl FnMut(&SocketAddr, &Message),
+) -> Result<(), CommError> {
+    let label = config.server_label();
+    let codec = config.codec();
+    let hex_dump = config.verbose >= HEX_DUMP_LEVEL;
+    info!("[{}] echo モードで動作します", label);
+
+    let end_at = config.end_at(Instant::now());
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    loop {
+        let received = tokio::select! {
+            r = recv_from_any(sockets, &mut buf) => r,
+            _ = &mut ctrl_c => {
+                warn!("[{}] Ctrl-C を受信。終了します。", label);
+                return Ok(());
+            }
+            _ = time::sleep_until(end_at.unwrap_or_else(Instant::now)), if end_at.is_some() => {
+                warn!("[{}] --max-duration-secs を過ぎました。終了します。", label);
+                return Err(config.max_duration_error());
+            }
+        };
+        let (local, n, addr) = match received {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("[{}] recv_from エラー: {}", label, e);
+                continue;
+            }
+        };
+        metrics.on_recv(n);
+        if !config.allows(&addr) {
+            warn!(addr = %addr, "[{}] 許可されていない送信元からのデータを捨てます: {}", label, addr);
+            continue;
+        }
+        warn_if_truncated(label, n, buf.len());
+        let data = &buf[..n];
+        if hex_dump {
+            log_hex_dump(label, data);
+        }
+
+        info!(
+            target: PACKET,
+            addr = %addr,
+            "[{}] echo 受信 from {} ({} バイト): {}",
+            label,
+            addr,
+            n,
+            String::from_utf8_lossy(data)
+        );
+        match codec.decode(data) {
+            Ok(msg) => {
+                info!(target: PACKET, addr = %addr, "[{}] echo パース結果: {:?}", label, msg);
+                on_message(&addr, &msg);
+            }
+            Err(e) => {
+                info!(target: PACKET, addr = %addr, "[{}] echo パースできません: {}", label, e)
+            }
+        }
+
+        // 受信したバイト列をそのまま返す
+        sim.send_to(&sockets[local], data, addr, label, 0).await?;
+        metrics.on_send(n);
+    }
+}
+
+/// handle_message のあとにサーバのループをどうするか
+enum Flow {
+    /// 次の受信を待つ
+    Continue,
+    /// サーバを終了する (config.once で終わったセッションの受信ログを返す)
+    Stop(Option<RecvLog>),
+}
+
+/// run_server_with_sockets のループが持つ状態
+struct Server<'a> {
+    sockets: &'a [UdpSocket],
+    /// sockets と同じ順の、待ち受けているポート番号
+    ports: Vec<u16>,
+    config: &'a Config,
+    codec: Codec,
+    sim: NetSim,
+    /// クライアントのアドレスとセッション ID ごとのセッション状態
+    sessions: HashMap<(SocketAddr, u64), SessionState>,
+    /// ack_delay を指定したときの、送信待ちの Data の応答
+    delayed: VecDeque<DelayedSend>,
+    metrics: Metrics,
+    /// --state-file から読み込んだ前回の進捗。クライアントのアドレスごとに、そのアドレスで最初にできたセッションが引き継ぐ
+    resume: HashMap<SocketAddr, RecvLog>,
+}
+
+impl Server<'_> {
+    /// --state-file に、クライアントのアドレスごとの受信済みの no を書き出す
+    ///
+    /// 同じアドレスにセッションが複数あれば最後に受信したものを残す。
+    /// まだ戻ってきていないクライアントの前回の進捗も、そのまま残す
+    fn checkpoint(&self) -> Result<(), CommError> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+        let mut state: BTreeMap<SocketAddr, &BTreeSet<u32>> = self
+            .resume
+            .iter()
+            .map(|(&addr, log)| (addr, log.received()))
+            .collect();
+        let mut sessions: Vec<_> = self.sessions.iter().collect();
+        sessions.sort_by_key(|(_, session)| session.last_seen);
+        for (&(addr, _), session) in sessions {
+            state.insert(addr, session.recv_log.received());
+        }
+        save_json(path, &state)?;
+        Ok(())
+    }
+
+    /// --fast-count: decode_head で読んだ Data の no を受信ログに記録し、payload のない Ack で応答する
+    ///
+    /// Message を組み立てないぶん速いが、CRC の検証、--out、--bidi、--ack-delay などは効かない
+    async fn fast_count(
+        &mut self,
+        local: usize,
+        addr: SocketAddr,
+        head: Head,
+        n: usize,
+    ) -> Result<(), CommError> {
+        let label = self.config.server_label();
+        let session = self
+            .sessions
+            .entry((addr, head.session))
+            .or_insert_with(|| {
+                SessionState::new(
+                    addr,
+                    head.session,
+                    local,
+                    self.config,
+                    self.resume.remove(&addr),
+                )
+            });
+        session.last_seen = Instant::now();
+        session.bytes_received += n as u64;
+        session.recv_log.record(head.no);
+        info!(target: PACKET, addr = %addr, no = head.no, "[{}] 受信 from {}: no={}", label, addr, head.no);
+
+        let reply = Message::ack(head.no, Peer::Server)
+            .with_label(label)
+            .with_session(head.session)
+            .with_ack(session.recv_log.contiguous_prefix_max());
+        let data = self.codec.encode(&reply)?;
+        self.sim
+            .send_to(&self.sockets[local], &data, addr, label, reply.no)
+            .await?;
+        self.metrics.on_send(data.len());
+        session.last_msg = Some(reply);
+        Ok(())
+    }
+
+    /// パースできたメッセージを kind ごとに処理する。MsgKind を増やしたらここに腕を足す
+    ///
+    /// local は msg が届いたソケットの番号で、応答はそのソケットから返す
+    async fn handle_message(
+        &mut self,
+        local: usize,
+        addr: SocketAddr,
+        msg: Message,
+        n: usize,
+    ) -> Result<Flow, CommError> {
+        let socket = &self.sockets[local];
+        let label = self.config.server_label();
+        // 自分の応答が折り返してきた (ループしている) ものに応答すると、送り合いが止まらなくなる
+        if msg.from != Peer::Client {
+            warn!(
+                addr = %addr,
+                no = msg.no,
+                "[{}] クライアント以外からの {:?} を無視します from {}: from={:?}",
+                label,
+                msg.kind,
+                addr,
+                msg.from
+            );
+            return Ok(Flow::Continue);
+        }
+        match msg.kind {
+            MsgKind::Syn => {
+                // 新しいセッションなので、このクライアントの前の実行のセッションは捨てる
+                // (SYN の no はクライアントが最初に送る no。0 なら FIRST_NO から)
+                let mut state = SessionState::new(
+                    addr,
+                    msg.session,
+                    local,
+                    self.config,
+                    self.resume.remove(&addr),
+                );
+                state.recv_log = state.recv_log.with_first(msg.no);
+                self.sessions.retain(|&(a, _), _| a != addr);
+                self.sessions.insert((addr, msg.session), state);
+
+                let reply = Message::syn(0, Peer::Server)
+                    .with_label(label)
+                    .with_session(msg.session);
+                let data = self.codec.encode(&reply)?;
+                self.sim
+                    .send_to(socket, &data, addr, label, reply.no)
+                    .await?;
+                self.metrics.on_send(data.len());
+                info!(
+                    addr = %addr,
+                    client = %msg.label,
+                    "[{}] セッション開始 from {} ({}): {:?}",
+                    label,
+                    addr,
+                    msg.label,
+                    reply
+                );
+            }
+            MsgKind::Data => {
+                // SYN を送ってこないクライアントでも、最初の Data でセッションを作る
+                // (SYN なしで再起動したクライアントの Data も、新しいセッションとして受け付ける)
+                let session = self.sessions.entry((addr, msg.session)).or_insert_with(|| {
+                    SessionState::new(
+                        addr,
+                        msg.session,
+                        local,
+                        self.config,
+                        self.resume.remove(&addr),
+                    )
+                });
+                session.last_seen = Instant::now();
+                session.bytes_received += n as u64;
+
+                // --strict-order: 連続して受信済みの次より先の no が来たら、途中が抜けている
+                // (first から seq_next の順に数えて比べるので、u32::MAX の次の 1 は順序どおり)
+                let prefix = session.recv_log.contiguous_prefix_max();
+                let expected = if prefix == 0 {
+                    FIRST_NO
+                } else {
+                    next_no(prefix)
+                };
+                let first = session.recv_log.first();
+                if self.config.strict_order && seq_diff(first, msg.no) > seq_diff(first, expected) {
+                    error!(
+                        addr = %addr,
+                        no = msg.no,
+                        expected,
+                        "[{}] 順序違反: no={} を受信しましたが、次は no={} のはずです",
+                        label,
+                        msg.no,
+                        expected
+                    );
+                    if self.config.once {
+                        return Err(CommError::OutOfOrder {
+                            no: msg.no,
+                            expected,
+                        });
+                    }
+                }
+
+                // 受信ログを更新
+                let before = session.recv_log.total();
+                session.recv_log.record(msg.no);
+                let is_new = session.recv_log.total() > before;
+                if self.config.out.is_some() {
+                    session.payloads.insert(msg.no, msg.payload.clone());
+                }
+                info!(
+                    target: PACKET,
+                    "[{}-RECV {}] 欠落ログ: missing=[{}]",
+                    label,
+                    addr,
+                    session.recv_log.missing_ranges(session.recv_log.last())
+                );
+
+                // --bidi: 新しい no を受信するたびに、こちらからも自分の no で Push を 1 つ送る。
+                // 応答より先に送るので、クライアントは最後の応答を受信するまでにすべての Push を受信できる
+                // (Push は再送しないので、落ちた分はクライアントの受信ログに抜けとして残る)
+                if self.config.bidi && is_new {
+                    let push_no = seq_add(FIRST_NO, session.pushed);
+                    session.pushed += 1;
+                    let push = Message::push(push_no)
+                        .with_label(label)
+                        .with_session(msg.session)
+                        .with_payload(build_payload(push_no, self.config.payload_size));
+                    let data = self.codec.encode(&push)?;
+                    self.sim
+                        .send_to(socket, &data, addr, label, push.no)
+                        .await?;
+                    self.metrics.on_send(data.len());
+                    info!(
+                        target: PACKET,
+                        addr = %addr,
+                        no = push.no,
+                        bytes = data.len(),
+                        "[{}] Push 送信 to {}: {:?} bytes={}",
+                        label,
+                        addr,
+                        push,
+                        data.len()
+                    );
+                }
+
+                // クライアントから来た no と payload をそのまま返し、累積 ACK も載せる
+                // (--ack-only なら payload を返さず Ack にする)
+                // 新規応答なので retry=0
+                let reply = if self.config.ack_only {
+                    Message::ack(msg.no, Peer::Server)
+                } else {
+                    Message::data(msg.no, Peer::Server).with_payload(msg.payload)
+                }
+                .with_label(label)
+                .with_session(msg.session)
+                .with_ack(session.recv_log.contiguous_prefix_max());
+                // --corrupt-ack-rate: 送るものだけ no を 1 つずらす (再送用の last_msg は正しいまま)
+                let data = if self.sim.should_corrupt() {
+                    let wrong = Message {
+                        no: next_no(reply.no),
+                        ..reply.clone()
+                    };
+                    info!(
+                        target: PACKET,
+                        addr = %addr,
+                        no = reply.no,
+                        "[{}] わざと no={} の応答を no={} で送ります to {}",
+                        label,
+                        reply.no,
+                        wrong.no,
+                        addr
+                    );
+                    self.codec.encode(&wrong)?
+                } else {
+                    self.codec.encode(&reply)?
+                };
+                let bytes = data.len();
+                // --reply-count: 同じ応答を reply_count 回続けて送る (2 回目からはクライアントで重複になる)
+                for _ in 0..self.config.reply_count {
+                    if self.config.ack_delay.is_zero() {
+                        self.sim
+                            .send_to(socket, &data, addr, label, reply.no)
+                            .await?;
+                        self.metrics.on_send(data.len());
+                    } else {
+                        // 待っている間も他の受信を続けられるように、送信は後回しにする
+                        self.delayed.push_back(DelayedSend {
+                            due: Instant::now() + self.config.ack_delay,
+                            local,
+                            addr,
+                            no: reply.no,
+                            data: data.clone(),
+                        });
+                    }
+                }
+                info!(
+                    target: PACKET,
+                    addr = %addr,
+                    no = reply.no,
+                    bytes,
+                    "[{}] 送信 to {}: {:?} bytes={}",
+                    label,
+                    addr,
+                    reply,
+                    bytes
+                );
+
+                // 再送用に記録
+                session.last_msg = Some(reply);
+            }
+            MsgKind::Fin => {
+                info!(addr = %addr, "[{}] FIN 受信 from {}: {:?}", label, addr, msg);
+                let key = (addr, msg.session);
+                if !self.sessions.contains_key(&key)
+                    && let Some(&(_, current)) = self.sessions.keys().find(|(a, _)| *a == addr)
+                {
+                    warn!(
+                        addr = %addr,
+                        "[{}] 別セッションの FIN を無視します from {}: session={} (現在: {})",
+                        label,
+                        addr,
+                        msg.session,
+                        current
+                    );
+                    return Ok(Flow::Continue);
+                }
+
+                if msg.no != FIN_NO {
+                    warn!(
+                        addr = %addr,
+                        "[{}] FIN の no が {} ではありません from {}: no={}",
+                        label,
+                        FIN_NO,
+                        addr,
+                        msg.no
+                    );
+                }
+
+                // FIN-ACK を返してから、このクライアントのセッションを片付ける
+                let reply = Message::fin(Peer::Server)
+                    .with_label(label)
+                    .with_session(msg.session);
+                let data = self.codec.encode(&reply)?;
+                self.sim
+                    .send_to(socket, &data, addr, label, reply.no)
+                    .await?;
+                self.metrics.on_send(data.len());
+                info!(addr = %addr, "[{}] FIN-ACK 送信 to {}: {:?}", label, addr, reply);
+
+                // FIN-ACK が失われて FIN が再送されてきた場合は、もうセッションがない
+                let finished = self.sessions.remove(&key);
+                if let Some(session) = &finished {
+                    if let Some(stats) = msg.stats {
+                        session.reconcile(addr, stats, label);
+                    }
+                    session.finish(addr, self.config, &self.metrics)?;
+                    self.metrics.duplicates += session.recv_log.duplicates();
+                }
+                // 転送が終わったので、次の転送に前回の進捗を持ち込まないように、このクライアントの分を消す
+                // (他のクライアントの進捗が残っていなければファイルごと消す。セッションのない FIN では何もしない)
+                if let Some(path) = &self.config.state_file
+                    && finished.is_some()
+                {
+                    if self.sessions.is_empty() && self.resume.is_empty() {
+                        match fs::remove_file(path) {
+                            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                            _ => {}
+                        }
+                    } else {
+                        self.checkpoint()?;
+                    }
+                }
+
+                if self.config.once {
+                    // ここでプロセス終了（ループを抜ける）
+                    return Ok(Flow::Stop(finished.map(|session| session.recv_log)));
+                }
+                info!(addr = %addr, "[{}] client {} finished, waiting for more", label, addr);
+            }
+            MsgKind::Ping => {
+                // 生存確認なのでセッションは延命するが、受信ログには記録しない
+                if let Some(session) = self.sessions.get_mut(&(addr, msg.session)) {
+                    session.last_seen = Instant::now();
+                }
+
+                let reply = Message::pong().with_label(label).with_session(msg.session);
+                let data = self.codec.encode(&reply)?;
+                self.sim
+                    .send_to(socket, &data, addr, label, reply.no)
+                    .await?;
+                self.metrics.on_send(data.len());
+                info!(target: PACKET, addr = %addr, "[{}] Pong 送信 to {}: {:?}", label, addr, reply);
+            }
+            MsgKind::Rst => {
+                // 異常終了なので、まとめも出さずにセッションを捨てる (返事もしない)
+                warn!(addr = %addr, "[{}] RST 受信 from {}: {:?}", label, addr, msg);
+                if self.sessions.remove(&(addr, msg.session)).is_some() {
+                    warn!(addr = %addr, "[{}] セッションを破棄しました: {}", label, addr);
+                } else if self.sessions.keys().any(|(a, _)| *a == addr) {
+                    warn!(addr = %addr, "[{}] 別セッションの RST を無視します from {}", label, addr);
+                }
+            }
+            MsgKind::Ack if self.config.bidi => {
+                // --bidi: クライアントが Push を受信した印。Push は再送しないので、記録するだけ
+                match self.sessions.get_mut(&(addr, msg.session)) {
+                    Some(session) => {
+                        session.last_seen = Instant::now();
+                        session.push_log.record(msg.no);
+                        info!(
+                            target: PACKET,
+                            addr = %addr,
+                            no = msg.no,
+                            "[{}] Push の Ack 受信 from {}",
+                            label,
+                            addr
+                        );
+                    }
+                    _ => {
+                        warn!(
+                            addr = %addr,
+                            "[{}] セッションのない Ack を無視します from {}: {:?}",
+                            label,
+                            addr,
+                            msg
+                        );
+                    }
+                }
+            }
+            MsgKind::Stat => {
+                // セッションは作らず、いまのセッション一覧をアドレス順に JSON で返す
+                let mut stats: Vec<SessionStat> = self
+                    .sessions
+                    .iter()
+                    .map(|(&(addr, _), session)| session.stat(addr))
+                    .collect();
+                stats.sort_by_key(|s| (s.addr, s.session));
+                let reply = Message::stat(Peer::Server)
+                    .with_label(label)
+                    .with_session(msg.session)
+                    .with_payload(serde_json::to_vec(&stats)?);
+                let data = self.codec.encode(&reply)?;
+                self.sim
+                    .send_to(socket, &data, addr, label, reply.no)
+                    .await?;
+                self.metrics.on_send(data.len());
+                info!(
+                    addr = %addr,
+                    "[{}] Stat 送信 to {}: セッション {} 個",
+                    label,
+                    addr,
+                    stats.len()
+                );
+            }
+            MsgKind::Nack | MsgKind::Pong | MsgKind::Ack | MsgKind::Push => {
+                // NACK / Pong / Push はサーバからしか送らない (Ack も --bidi でなければ送られてこない)
+                warn!(addr = %addr, "[{}] 想定外の {:?} from {}: {:?}", label, msg.kind, addr, msg);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// サーバ処理
+///
+/// config.bind_addr() で待ち受ける。
+/// クライアントのアドレスとセッション ID の組をキーにしてセッション状態を持ち、複数のクライアントを同時に扱う。
+/// 同じアドレスから別のセッション ID の Data が来たら、別のセッションとして受け付ける (SYN なしで再起動したクライアントなど)。
+/// SYN を受信したら、同じアドレスの前の実行のセッションは捨てる。
+/// FIN を受信したらそのクライアントのセッションだけを片付け、他のクライアントの処理を続ける。
+/// config.once が true なら、最初の FIN でサーバ自体も終了する。
+/// config.session_timeout の間なにも受信しなかったセッションは、クライアントが落ちたとみなして捨てる。
+/// Ctrl-C を受けたら、残っているセッションの受信ログのまとめを出して終了する。
+/// 送信は config.sim() を通すので、パケットロスや遅延を模擬できる。
+/// config.no_server_resend が true なら、タイムアウトしても直前の応答を再送しない。
+/// config.ack_delay を指定すると、Data の応答をその時間だけ遅らせて送る (その間も受信は続ける)。
+/// config.json_summary が true なら、セッションの終了ごとに受信ログのまとめを JSON で標準出力に出す。
+/// config.bidi が true なら、Data を受信するたびにこちらからも自分の no で Push を送る。
+/// config.reply_count が 2 以上なら、Data の応答をその回数だけ続けて送る。
+/// config.corrupt_ack_rate を指定すると、その確率で Data の応答の no を 1 つずらして送る。
+/// Stat を受信したら、セッション一覧 (SessionStat) を JSON にして Stat で返す。
+/// config.max_duration が過ぎたら、Ctrl-C と同じく後始末をしてから CommError::MaxDuration を返す。
+/// config.echo が true なら、状態を持たずに受信したデータをそのまま返すだけにする。
+/// config.fast_count が true なら、Data は Message を組み立てずに fast_count で数える (on_message も呼ばない)。
+/// config.dual_stack が true なら、ポートごとに bind_dual_stack で IPv4 と IPv6 の両方を待ち受ける。
+/// 終了時に送受信のカウンタを返す。config.once で終わった場合は、終了したセッションの受信ログも返す
+pub async fn run_server(config: &Config) -> Result<(Option<RecvLog>, Metrics), CommError> {
+    let mut sockets = Vec::new();
+    for bind_addr in config.bind_addrs() {
+        if config.dual_stack {
+            sockets.extend(bind_dual_stack(bind_addr.port(), config.server_label())?);
+            continue;
+        }
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|source| CommError::Bind {
+                addr: bind_addr,
+                source,
+            })?;
+        sockets.push(socket);
+    }
+    run_server_with_sockets(sockets, config).await
+}
+
+/// port で IPv4 と IPv6 の両方を受け付けるソケットを作る (--dual-stack)
+///
+/// まず IPV6_V6ONLY を外した [::] のソケット 1 つで、v4-mapped のアドレスも受け付ける。
+/// OS や設定で外せなければ警告を出して、[::] と 0.0.0.0 の 2 つのソケットに分ける。
+/// IPv6 のソケット自体が作れなければ、警告を出して 0.0.0.0 だけで待ち受ける
+fn bind_dual_stack(port: u16, label: &str) -> Result<Vec<UdpSocket>, CommError> {
+    let v6_addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    let v4_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let bind_error = |addr, source| CommError::Bind { addr, source };
+
+    let socket = match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(
+                "[{}] IPv6 のソケットを作れないので {} だけで待ち受けます: {}",
+                label, v4_addr, e
+            );
+            let socket =
+                bind_std(Domain::IPV4, v4_addr, None).map_err(|e| bind_error(v4_addr, e))?;
+            return Ok(vec![socket]);
+        }
+    };
+    if let Err(e) = socket.set_only_v6(false) {
+        warn!(
+            "[{}] IPV6_V6ONLY を外せないので {} と {} の 2 つで待ち受けます: {}",
+            label, v6_addr, v4_addr, e
+        );
+        drop(socket);
+        let v6 = bind_std(Domain::IPV6, v6_addr, Some(true)).map_err(|e| bind_error(v6_addr, e))?;
+        // port が 0 なら、IPv4 も IPv6 に割り当てられたポートに揃える
+        let v4_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, v6.local_addr()?.port()));
+        let v4 = bind_std(Domain::IPV4, v4_addr, None).map_err(|e| bind_error(v4_addr, e))?;
+        return Ok(vec![v6, v4]);
+    }
+    let socket = into_tokio(socket, v6_addr).map_err(|e| bind_error(v6_addr, e))?;
+    info!(
+        "[{}] dual-stack: {} で IPv4 (v4-mapped) と IPv6 を待ち受けます",
+        label,
+        socket.local_addr()?
+    );
+    Ok(vec![socket])
+}
+
+/// domain のソケットを作って addr にバインドする (only_v6 が Some なら IPV6_V6ONLY をその値にする)
+fn bind_std(domain: Domain, addr: SocketAddr, only_v6: Option<bool>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(only_v6) = only_v6 {
+        socket.set_only_v6(only_v6)?;
+    }
+    into_tokio(socket, addr)
+}
+
+/// socket2 のソケットを addr にバインドして tokio の UdpSocket にする
+fn into_tokio(socket: Socket, addr: SocketAddr) -> io::Result<UdpSocket> {
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// バインド済みの socket で待ち受ける run_server (テストや組み込み用)。config.port / config.bind は使わない
+pub async fn run_server_with_socket(
+    socket: UdpSocket,
+    config: &Config,
+) -> Result<(Option<RecvLog>, Metrics), CommError> {
+    run_server_with_sockets(vec![socket], config).await
+}
+
+/// バインド済みの複数の socket で同時に待ち受ける run_server。応答はクライアントが送ってきたソケットから返す
+pub async fn run_server_with_sockets(
+    sockets: Vec<UdpSocket>,
+    config: &Config,
+) -> Result<(Option<RecvLog>, Metrics), CommError> {
+    run_server_with_hook(sockets, config, |_, _| {}).await
+}
+
+/// パースできたメッセージごとに on_message を呼ぶ run_server_with_sockets (組み込み用)
+///
+/// on_message は送信元の確認や状態の更新より前に、受信した順に呼ばれる (--echo でも呼ぶ)。
+/// 自分の監視に数を送ったり、独自のログを出したりするのに使う
+pub async fn run_server_with_hook(
+    sockets: Vec<UdpSocket>,
+    config: &Config,
+    mut on_message: impl FnMut(&SocketAddr, &Message),
+) -> Result<(Option<RecvLog>, Metrics), CommError> {
+    config.validate()?;
+    let label = config.server_label();
+    let &Config {
+        timeout,
+        max_retries,
+        session_timeout,
+        no_server_resend,
+        ..
+    } = config;
+    let codec = config.codec();
+    let mut sim = config.sim();
+
+    if sockets.is_empty() {
+        return Err(CommError::BadArgs(
+            "待ち受けるソケットがありません".to_string(),
+        ));
+    }
+    let mut ports = Vec::new();
+    for socket in &sockets {
+        let local_addr = socket.local_addr()?;
+        info!(addr = %local_addr, "[{}] 起動: {}", label, local_addr);
+        if let Some(dscp) = config.dscp {
+            apply_dscp(socket, dscp, label);
+        }
+        ports.push(local_addr.port());
+    }
+
+    let mut metrics = Metrics::default();
+    let hex_dump = config.verbose >= HEX_DUMP_LEVEL;
+
+    let resume = match &config.state_file {
+        Some(path) => load_state(path, label)?,
+        None => HashMap::new(),
+    };
+    if let Some(path) = &config.state_file {
+        for (addr, log) in &resume {
+            info!(
+                addr = %addr,
+                "[{}] {} から {} の前回の進捗を読み込みました: ranges=[{}]",
+                label,
+                path.display(),
+                addr,
+                log.build_ranges_summary()
+            );
+        }
+    }
+
+    if config.echo {
+        let result = run_echo(&sockets, &mut sim, &mut metrics, config, &mut on_message).await;
+        // --max-duration-secs で打ち切ったときも、カウンタは出してから終わる
+        metrics.log(label);
+        result?;
+        return Ok((None, metrics));
+    }
+
+    let mut server = Server {
+        sockets: &sockets,
+        ports,
+        config,
+        codec,
+        sim,
+        sessions: HashMap::new(),
+        delayed: VecDeque::new(),
+        metrics,
+        resume,
+    };
+
+    // 最後に NACK を送った (抜けを確認した) 時刻
+    let mut last_nack = Instant::now();
+    let end_at = config.end_at(Instant::now());
+    // end_at を過ぎて打ち切ったか
+    let mut expired = false;
+
+    // Ctrl-C で止めたときも、残っているセッションのまとめを出してから終わる
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    // 受信のたびに確保し直さないよう、ループの外で 1 回だけ確保する
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    loop {
+        // timeout ごとに、放置されたセッションを捨ててから、各セッションの受信ログの抜けを NACK で知らせる
+        if last_nack.elapsed() >= timeout {
+            server.sessions.retain(|(addr, _), session| {
+                let alive = session.last_seen.elapsed() < session_timeout;
+                if !alive {
+                    warn!(addr = %addr, "[{}] evicting idle session {}", label, addr);
+                    server.metrics.duplicates += session.recv_log.duplicates();
+                }
+                alive
+            });
+            for ((addr, _), session) in &server.sessions {
+                send_nack(
+                    &sockets[session.local],
+                    codec,
+                    &mut server.sim,
+                    session,
+                    *addr,
+                    &mut server.metrics,
+                    label,
+                )
+                .await?;
+            }
+            server.checkpoint()?;
+            last_nack = Instant::now();
+        }
+
+        // クライアントからのデータを timeout だけ待つ
+        let next_due = server.delayed.front().map(|d| d.due);
+        let received = tokio::select! {
+            r = time::timeout(timeout, recv_from_any(&sockets, &mut buf)) => r,
+            _ = &mut ctrl_c => {
+                warn!("[{}] Ctrl-C を受信。終了します。", label);
+                break;
+            }
+            _ = time::sleep_until(end_at.unwrap_or_else(Instant::now)), if end_at.is_some() => {
+                warn!("[{}] --max-duration-secs を過ぎました。終了します。", label);
+                expired = true;
+                break;
+            }
+            _ = time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                // ack_delay が過ぎた Data の応答を送る (delay はどれも同じなので先頭から順に期限が来る)
+                while let Some(d) = server.delayed.pop_front_if(|d| d.due <= Instant::now()) {
+                    server.sim.send_to(&sockets[d.local], &d.data, d.addr, label, d.no).await?;
+                    server.metrics.on_send(d.data.len());
+                }
+                continue;
+            }
+        };
+        match received {
+            // 受信できた
+            Ok(Ok((local, n, addr))) => {
+                server.metrics.on_recv(n);
+                if !config.allows(&addr) {
+                    warn!(addr = %addr, "[{}] 許可されていない送信元からのデータを捨てます: {}", label, addr);
+                    continue;
+                }
+                if hex_dump {
+                    log_hex_dump(label, &buf[..n]);
+                }
+                warn_if_truncated(label, n, buf.len());
+                // --fast-count: Data だけは先頭のフィールドを読んで数える (それ以外はいつもどおり)
+                if config.fast_count
+                    && let Ok(head) = server.codec.decode_head(&buf[..n])
+                    && head.kind == MsgKind::Data
+                    && head.from == Peer::Client
+                {
+                    server.fast_count(local, addr, head, n).await?;
+                    continue;
+                }
+                let text = String::from_utf8_lossy(&buf[..n]);
+                match codec.decode(&buf[..n]) {
+                    Ok(msg) => {
+                        info!(
+                            target: PACKET,
+                            addr = %addr,
+                            no = msg.no,
+                            retry = msg.retry,
+                            port = server.ports[local],
+                            bytes = n,
+                            "[{}] 受信 from {} (port {}): {:?} bytes={}{}",
+                            label,
+                            addr,
+                            server.ports[local],
+                            msg,
+                            n,
+                            msg.one_way_label()
+                        );
+
+                        on_message(&addr, &msg);
+                        match server.handle_message(local, addr, msg, n).await? {
+                            Flow::Continue => {}
+                            Flow::Stop(recv_log) => {
+                                server.metrics.log(label);
+                                return Ok((recv_log, server.metrics));
+                            }
+                        }
+                    }
+                    Err(DecodeError::CrcMismatch) => {
+                        // 壊れたパケットとして捨て、クライアントの再送に任せる
+                        warn!("[{}] CRC mismatch, discarding", label);
+                    }
+                    Err(e @ DecodeError::VersionMismatch { .. }) => {
+                        // 相手が古い (または新しい) バイナリなので、パースせずに捨てる
+                        warn!(addr = %addr, "[{}] {}", label, e);
+                    }
+                    Err(e) => {
+                        warn!("[{}] {} / 生データ: {}", label, e, text);
+                    }
+                }
+            }
+            // recv_from 自体のエラー
+            Ok(Err(e)) => {
+                warn!("[{}] recv_from エラー: {}", label, e);
+            }
+            // --no-server-resend なら、クライアントへの応答だけにして自分からは再送しない
+            Err(_) if no_server_resend => {}
+            // タイムアウト: 各セッションの直前のメッセージを retry+1 して再送
+            Err(_) => {
+                let mut resent = false;
+                for ((addr, _), session) in server.sessions.iter_mut() {
+                    let Some(msg) = session.last_msg.as_mut() else {
+                        // まだ何も送ったことがない場合は何もしない
+                        continue;
+                    };
+                    // 直前に送信したメッセージが Data の場合だけ再送（FIN は再送しない）
+                    if !msg.kind.is_data_reply() {
+                        continue;
+                    }
+                    if msg.retry >= max_retries {
+                        // 上限に達したら再送をあきらめ、次の受信を待つだけにする
+                        error!(
+                            addr = %addr,
+                            no = msg.no,
+                            retries = msg.retry,
+                            "[{}] no={} gave up after {} retries",
+                            label,
+                            msg.no,
+                            msg.retry
+                        );
+                        session.last_msg = None;
+                        continue;
+                    }
+                    msg.retry += 1;
+                    msg.sent_at = unix_millis();
+                    let data = codec.encode(msg)?;
+                    info!(
+                        target: PACKET,
+                        addr = %addr,
+                        no = msg.no,
+                        retry = msg.retry,
+                        bytes = data.len(),
+                        "[{}] タイムアウト、再送 to {}: {:?} bytes={}",
+                        label,
+                        addr,
+                        msg,
+                        data.len()
+                    );
+                    if msg.retry % RESEND_WARN_EVERY == 0 {
+                        warn!(
+                            addr = %addr,
+                            no = msg.no,
+                            retry = msg.retry,
+                            "[{}] no={} を {} 回再送しても次の Data が届きません (クライアントからの通信が失われている可能性があります)",
+                            label,
+                            msg.no,
+                            msg.retry
+                        );
+                    }
+                    server
+                        .sim
+                        .send_to(&sockets[session.local], &data, *addr, label, msg.no)
+                        .await?;
+                    server.metrics.on_send(data.len());
+                    server.metrics.retransmits += 1;
+                    resent = true;
+                }
+                if resent {
+                    server.metrics.timeouts += 1;
+                }
+            }
+        }
+    }
+
+    // 途中で止めたときは、次の起動で続きから受信できるように残しておく
+    server.checkpoint()?;
+    for ((addr, _), session) in &server.sessions {
+        session.finish(*addr, config, &server.metrics)?;
+        server.metrics.duplicates += session.recv_log.duplicates();
+    }
+    server.metrics.log(label);
+    if expired {
+        return Err(config.max_duration_error());
+    }
+    Ok((None, server.metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_path_has_one_file_per_session() {
+        assert_eq!(
+            session_csv_path(Path::new("/tmp/recv.csv"), 42),
+            Path::new("/tmp/recv-42.csv")
+        );
+        assert_eq!(session_csv_path(Path::new("recv"), 7), Path::new("recv-7"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::io;
+use std::net::SocketAddr;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration};
+use tracing::info;
+
+use crate::PACKET;
+
+/// 再送処理の確認用に、送信パケットをわざと落としたり遅らせたりするシミュレータ
+///
+/// 遅延は送信の前に待つだけなので、delay + jitter が応答待ちタイムアウトに近いと
+/// 届くはずの応答もタイムアウト扱いになり、再送が増える
+pub struct NetSim {
+    /// 送信を捨てる確率 (0.0 - 1.0)
+    drop_rate: f64,
+    /// 送信前に必ず待つ時間
+    delay: Duration,
+    /// delay に足す、0 からこの値までのランダムな待ち時間
+    jitter: Duration,
+    /// サーバが Data の応答の no をわざとずらす確率 (0.0 - 1.0)
+    corrupt_rate: f64,
+    rng: StdRng,
+}
+
+impl NetSim {
+    /// seed を指定すると、どのパケットを落とすかが毎回同じになる
+    pub fn new(drop_rate: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Self {
+            drop_rate,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            corrupt_rate: 0.0,
+            rng,
+        }
+    }
+
+    /// 送信のたびに delay + (0..=jitter) だけ待つようにする
+    pub fn with_delay(mut self, delay: Duration, jitter: Duration) -> Self {
+        self.delay = delay;
+        self.jitter = jitter;
+        self
+    }
+
+    /// should_corrupt が corrupt_rate の確率で true を返すようにする
+    pub fn with_corrupt_rate(mut self, corrupt_rate: f64) -> Self {
+        self.corrupt_rate = corrupt_rate;
+        self
+    }
+
+    /// この応答の no をずらすかどうか (0.0 なら乱数を引かないので、drop_rate の結果は変わらない)
+    pub(crate) fn should_corrupt(&mut self) -> bool {
+        self.corrupt_rate > 0.0 && self.rng.random_bool(self.corrupt_rate)
+    }
+
+    /// この送信を捨てるかどうか
+    fn should_drop(&mut self) -> bool {
+        self.drop_rate > 0.0 && self.rng.random_bool(self.drop_rate)
+    }
+
+    /// 今回の送信前に待つ時間
+    fn next_delay(&mut self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let jitter = self.rng.random_range(Duration::ZERO..=self.jitter);
+        self.delay + jitter
+    }
+
+    /// 遅延を模擬する (ランタイムを止めないように tokio の sleep で待つ)
+    async fn wait(&mut self) {
+        let delay = self.next_delay();
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+    }
+
+    /// socket.send の代わりに使う。捨てたときも送れたことにする
+    pub(crate) async fn send(
+        &mut self,
+        socket: &UdpSocket,
+        data: &[u8],
+        label: &str,
+        no: u32,
+    ) -> io::Result<()> {
+        self.wait().await;
+        if self.should_drop() {
+            info!(target: PACKET, no, "[{}] simulated drop of no={}", label, no);
+            return Ok(());
+        }
+        socket.send(data).await?;
+        Ok(())
+    }
+
+    /// socket.send_to の代わりに使う。捨てたときも送れたことにする
+    pub(crate) async fn send_to(
+        &mut self,
+        socket: &UdpSocket,
+        data: &[u8],
+        addr: SocketAddr,
+        label: &str,
+        no: u32,
+    ) -> io::Result<()> {
+        self.wait().await;
+        if self.should_drop() {
+            info!(target: PACKET, addr = %addr, no, "[{}] simulated drop of no={}", label, no);
+            return Ok(());
+        }
+        socket.send_to(data, addr).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drops(sim: &mut NetSim, n: usize) -> Vec<bool> {
+        (0..n).map(|_| sim.should_drop()).collect()
+    }
+
+    #[test]
+    fn zero_rate_never_drops() {
+        let mut sim = NetSim::new(0.0, Some(1));
+        assert!(drops(&mut sim, 100).iter().all(|d| !d));
+    }
+
+    #[test]
+    fn full_rate_always_drops() {
+        let mut sim = NetSim::new(1.0, Some(1));
+        assert!(drops(&mut sim, 100).iter().all(|d| *d));
+    }
+
+    #[test]
+    fn same_seed_same_drops() {
+        let a = drops(&mut NetSim::new(0.3, Some(42)), 100);
+        let b = drops(&mut NetSim::new(0.3, Some(42)), 100);
+        assert_eq!(a, b);
+        assert!(a.iter().any(|d| *d));
+        assert!(a.iter().any(|d| !d));
+    }
+
+    #[test]
+    fn corrupt_rate_does_not_change_drops_when_zero() {
+        let mut sim = NetSim::new(0.3, Some(42)).with_corrupt_rate(0.0);
+        let mut expected = NetSim::new(0.3, Some(42));
+        for _ in 0..100 {
+            assert!(!sim.should_corrupt());
+            assert_eq!(sim.should_drop(), expected.should_drop());
+        }
+
+        let mut sim = NetSim::new(0.0, Some(1)).with_corrupt_rate(1.0);
+        assert!((0..100).all(|_| sim.should_corrupt()));
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let delay = Duration::from_millis(10);
+        let jitter = Duration::from_millis(5);
+        let mut sim = NetSim::new(0.0, Some(7)).with_delay(delay, jitter);
+        for _ in 0..100 {
+            let d = sim.next_delay();
+            assert!(d >= delay && d <= delay + jitter);
+        }
+
+        let mut sim = NetSim::new(0.0, Some(7)).with_delay(delay, Duration::ZERO);
+        assert_eq!(sim.next_delay(), delay);
+    }
+}
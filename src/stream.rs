@@ -0,0 +1,94 @@
+use std::io;
+use std::net::SocketAddr;
+
+use futures_util::Stream;
+use futures_util::stream;
+use tokio::net::UdpSocket;
+use tracing::{error, warn};
+
+use crate::codec::Codec;
+use crate::config::Config;
+use crate::message::Message;
+use crate::{PACKET, RECV_BUF_SIZE, warn_if_truncated};
+
+/// message_stream のログの接頭辞
+const LABEL: &str = "STREAM";
+
+/// socket に届いたデータグラムを、デフォルトの設定 (JSON + CRC) の Message にして順に返す
+///
+/// パースできないデータグラムと、ICMP の到達不能による受信エラーはログに出して読み飛ばす。
+/// それ以外の受信エラーは受信し直しても失敗し続けるので、ログに出してストリームを終える。
+/// 応答はしないので、返事が要るなら受け取った側が socket を共有して送ること
+pub fn message_stream(socket: UdpSocket) -> impl Stream<Item = (SocketAddr, Message)> {
+    message_stream_with_codec(socket, Config::default().codec())
+}
+
+/// codec でパースする message_stream (--format bincode や --no-crc の相手用)
+pub fn message_stream_with_codec(
+    socket: UdpSocket,
+    codec: Codec,
+) -> impl Stream<Item = (SocketAddr, Message)> {
+    let buf = vec![0u8; RECV_BUF_SIZE];
+    stream::unfold((socket, buf), move |(socket, mut buf)| async move {
+        loop {
+            let (n, addr) = match socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(e) if is_retryable(&e) => {
+                    warn!("[{}] recv_from エラー: {}", LABEL, e);
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "[{}] recv_from エラーのためストリームを終えます: {}",
+                        LABEL, e
+                    );
+                    return None;
+                }
+            };
+            warn_if_truncated(LABEL, n, buf.len());
+            match codec.decode(&buf[..n]) {
+                Ok(msg) => return Some(((addr, msg), (socket, buf))),
+                Err(e) => {
+                    warn!(
+                        target: PACKET,
+                        addr = %addr,
+                        "[{}] パースできないデータを捨てます from {}: {}",
+                        LABEL,
+                        addr,
+                        e
+                    );
+                }
+            }
+        }
+    })
+}
+
+/// 受信し直せば続けられる recv_from のエラーか
+///
+/// 前に送ったデータグラムへの ICMP の到達不能が、次の受信で ConnectionRefused (Windows では ConnectionReset) になる。
+/// 受信ごとに 1 回だけ出るので、読み飛ばしても空回りしない
+fn is_retryable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_icmp_errors_are_retried() {
+        assert!(is_retryable(&io::Error::from(
+            io::ErrorKind::ConnectionRefused
+        )));
+        assert!(is_retryable(&io::Error::from(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(!is_retryable(&io::Error::from(io::ErrorKind::NotConnected)));
+        assert!(!is_retryable(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+}
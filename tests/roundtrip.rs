@@ -0,0 +1,1031 @@
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use udp_tool::{
+    CommError, Config, Message, Metrics, MsgKind, Peer, RecvLog, message_stream, query_stat,
+    run_client, run_client_with_socket, run_server, run_server_with_hook, run_server_with_socket,
+    send_once,
+};
+
+type ServerHandle = JoinHandle<Result<(Option<RecvLog>, Metrics), CommError>>;
+
+/// ループバックの空いているポートでサーバを動かし、そのアドレスと終わりを待つハンドルを返す
+async fn spawn_server(config: &Config) -> (SocketAddr, ServerHandle) {
+    spawn_server_with_hook(config, |_, _| {}).await
+}
+
+/// パースできたメッセージごとに on_message を呼ぶ spawn_server
+async fn spawn_server_with_hook(
+    config: &Config,
+    on_message: impl FnMut(&SocketAddr, &Message) + Send + 'static,
+) -> (SocketAddr, ServerHandle) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_hook(vec![socket], &config, on_message).await });
+    (addr, server)
+}
+
+/// --once のサーバへクライアントが 1 回転送し、両方の受信ログとカウンタを返す
+async fn run_pair(
+    server_config: &Config,
+    client_config: &Config,
+) -> ((RecvLog, Metrics), (RecvLog, Metrics)) {
+    let (server_addr, server) = spawn_server(server_config).await;
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = run_client_with_socket(client_socket, server_addr, client_config)
+        .await
+        .expect("client gave up");
+    let (server_log, server_metrics) = tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+    let server_log = server_log.expect("server returned no session log");
+    (client, (server_log, server_metrics))
+}
+
+/// ループバック上でサーバとクライアントを同じプロセスで動かし、20 個の no をやり取りする
+#[tokio::test]
+async fn client_and_server_exchange_all_messages() {
+    let config = Config {
+        count: 20,
+        max_retries: 20,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let ((client_log, _), (server_log, _)) = run_pair(&config, &config).await;
+    assert_eq!(client_log.total(), 20);
+    assert_eq!(server_log.total(), 20);
+    assert_eq!(server_log.min(), 1);
+    assert_eq!(server_log.max(), 20);
+    assert!(server_log.missing(1).is_empty());
+}
+
+/// サーバの応答が落ちても、後の応答の累積 ACK で届いたとわかれば再送せずに応答済みにする
+#[tokio::test]
+async fn cumulative_ack_covers_dropped_echoes() {
+    let config = Config {
+        count: 50,
+        window: 8,
+        timeout: Duration::from_millis(30),
+        max_retries: 100,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let server_config = Config {
+        drop_rate: 0.3,
+        seed: Some(3),
+        ..config.clone()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // FIN に載るクライアントのまとめも、応答済みの数を数える
+    let mut fin_stats = None;
+    let server = run_server_with_hook(vec![server_socket], &server_config, |_, msg| {
+        if msg.kind == MsgKind::Fin {
+            fin_stats = msg.stats;
+        }
+    });
+    let client = run_client_with_socket(client_socket, server_addr, &config);
+    let (server_result, client_result) = tokio::join!(server, client);
+    let (client_log, metrics) = client_result.unwrap();
+    let stats = fin_stats.expect("FIN に stats がない");
+    assert_eq!((stats.sent, stats.acked), (50, 50));
+
+    // 落ちた応答は受信ログにないが、応答済みとしてはすべて数える
+    assert!(client_log.total() < 50);
+    assert_eq!(metrics.acked, 50);
+    let report = client_log.acked_report_line(&metrics, Duration::ZERO);
+    assert!(
+        report.starts_with("result=ok count=50 loss=0.0% "),
+        "{report}"
+    );
+    let summary = client_log.acked_summary(&metrics, Duration::ZERO);
+    assert_eq!((summary.total, summary.missing), (50, 0));
+
+    let (server_log, _) = server_result.unwrap();
+    assert_eq!(server_log.unwrap().total(), 50);
+}
+
+/// u32::MAX を過ぎて 1 に戻る no の範囲でも、クライアントとサーバで次に来るはずの no が一致する
+/// (応答が落ちても、FIRST に戻った後の累積 ACK で応答済みにできる)
+#[tokio::test]
+async fn nos_wrap_past_u32_max() {
+    let config = Config {
+        start_no: u32::MAX - 4,
+        count: 10,
+        window: 4,
+        timeout: Duration::from_millis(30),
+        max_retries: 100,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let server_config = Config {
+        drop_rate: 0.3,
+        seed: Some(3),
+        ..config.clone()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .unwrap();
+    assert_eq!(metrics.acked, 10);
+    let report = client_log.acked_report_line(&metrics, Duration::ZERO);
+    assert!(
+        report.starts_with("result=ok count=10 loss=0.0% "),
+        "{report}"
+    );
+
+    let (server_log, _) = server.await.unwrap().unwrap();
+    let server_log = server_log.unwrap();
+    assert_eq!(server_log.total(), 10);
+    assert!(server_log.missing(1).is_empty());
+    assert_eq!(server_log.contiguous_prefix_max(), 5);
+    assert_eq!(server_log.last(), 5);
+    assert_eq!(
+        server_log.build_ranges_summary(),
+        "4294967291-4294967295, 1-5"
+    );
+}
+
+/// --state-file は、セッションを持つ最後のクライアントの FIN でだけ消す
+#[tokio::test]
+async fn state_file_survives_stray_fins_and_other_sessions() {
+    let path = std::env::temp_dir().join(format!("udp_tool_state_{}", std::process::id()));
+    std::fs::write(&path, "{}").unwrap();
+    let config = Config {
+        state_file: Some(path.clone()),
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    // a と b がセッションを開き、c はセッションなしで FIN だけ送る
+    let codec = config.codec();
+    let exchange = async |socket: &UdpSocket, msg: Message| {
+        socket
+            .send_to(&codec.encode(&msg).unwrap(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 2048];
+        let n = socket.recv(&mut buf).await.unwrap();
+        codec.decode(&buf[..n]).unwrap()
+    };
+    let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let c = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    exchange(&a, Message::syn(1, Peer::Client).with_session(1)).await;
+    exchange(&b, Message::syn(1, Peer::Client).with_session(2)).await;
+
+    exchange(&c, Message::fin(Peer::Client).with_session(3)).await;
+    assert!(path.exists(), "セッションのない FIN で消えた");
+    exchange(&b, Message::fin(Peer::Client).with_session(2)).await;
+    assert!(path.exists(), "a の転送が続いているのに消えた");
+    exchange(&a, Message::fin(Peer::Client).with_session(1)).await;
+    assert!(!path.exists(), "最後のセッションの FIN で消えていない");
+
+    server.abort();
+}
+
+/// 大きい no から送っても、サーバの受信ログは 1 から連続した範囲になる
+#[tokio::test]
+async fn descending_order_fills_contiguous_prefix() {
+    let config = Config {
+        count: 20,
+        window: 4,
+        max_retries: 20,
+        descending: true,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, _) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert_eq!(client_log.total(), 20);
+
+    let (server_log, _) = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+    let server_log = server_log.expect("server returned no session log");
+    assert_eq!(server_log.total(), 20);
+    assert_eq!(server_log.contiguous_prefix_max(), 20);
+    assert_eq!(server_log.build_ranges_summary(), "1-20");
+}
+
+/// --bidi のサーバは Data ごとに Push も送るので、クライアントは Data の応答と合わせて 2 倍受信する
+#[tokio::test]
+async fn bidi_server_pushes_its_own_data() {
+    let config = Config {
+        count: 20,
+        max_retries: 20,
+        bidi: true,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert_eq!(client_log.total(), 20);
+    assert!(metrics.received >= 2 * u64::from(config.count));
+
+    let (server_log, _) = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        server_log.expect("server returned no session log").total(),
+        20
+    );
+}
+
+/// サーバの送信を落とすと、クライアントのタイムアウトと再送がカウンタに現れる
+#[tokio::test]
+async fn metrics_count_timeouts_under_loss() {
+    let config = Config {
+        count: 20,
+        max_retries: 50,
+        timeout: Duration::from_millis(20),
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let server_config = Config {
+        drop_rate: 0.3,
+        seed: Some(1),
+        ..config.clone()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert!(metrics.timeouts > 0);
+    assert!(metrics.retransmits > 0);
+    assert!(metrics.sent > u64::from(config.count));
+    assert!(metrics.bytes_sent > 0 && metrics.bytes_recv > 0);
+
+    let (_, server_metrics) = tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+    assert!(server_metrics.received >= u64::from(config.count));
+}
+
+/// 最初の DROPPED 個の Data にだけ応答しない相手とやり取りする。
+/// 時計を止めておけば待ち時間は tokio が進めるので、タイムアウトはちょうど DROPPED 回になる
+#[tokio::test(start_paused = true)]
+async fn paused_clock_counts_exact_timeouts() {
+    const DROPPED: u64 = 3;
+    // 止めた時計は、することがなくなるたびに次のタイマーまで一気に進む。
+    // 応答がソケットに届く前にクライアントのタイムアウトまで進まないように、1ms ずつ進める
+    let ticker = tokio::spawn(async {
+        loop {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    });
+    let config = Config {
+        count: 1,
+        max_retries: 10,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let codec = config.codec();
+    let server = tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        let mut ignored = 0;
+        loop {
+            let (n, from) = server_socket.recv_from(&mut buf).await.unwrap();
+            let msg = codec.decode(&buf[..n]).unwrap();
+            let reply = match msg.kind {
+                MsgKind::Syn => Message::syn(0, Peer::Server),
+                MsgKind::Data if ignored < DROPPED => {
+                    ignored += 1;
+                    continue;
+                }
+                MsgKind::Data => Message::data(msg.no, Peer::Server)
+                    .with_payload(msg.payload)
+                    .with_ack(msg.no),
+                MsgKind::Fin => Message::fin(Peer::Server),
+                _ => continue,
+            }
+            .with_session(msg.session);
+            let data = codec.encode(&reply).unwrap();
+            server_socket.send_to(&data, from).await.unwrap();
+            if reply.kind == MsgKind::Fin {
+                return;
+            }
+        }
+    });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert_eq!(client_log.total(), 1);
+    assert_eq!(metrics.timeouts, DROPPED);
+    assert_eq!(metrics.retransmits, DROPPED);
+    server.await.unwrap();
+    ticker.abort();
+}
+
+/// SYN にだけ応答する相手だと転送は終わらないが、--max-duration-secs で打ち切られる
+#[tokio::test(start_paused = true)]
+async fn max_duration_stops_a_stalled_transfer() {
+    let config = Config {
+        count: 10,
+        max_retries: 1000,
+        max_duration: Some(Duration::from_secs(5)),
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let codec = config.codec();
+    let server = tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (n, from) = server_socket.recv_from(&mut buf).await.unwrap();
+            let msg = codec.decode(&buf[..n]).unwrap();
+            if msg.kind == MsgKind::Syn {
+                let reply = Message::syn(0, Peer::Server).with_session(msg.session);
+                let data = codec.encode(&reply).unwrap();
+                server_socket.send_to(&data, from).await.unwrap();
+            }
+        }
+    });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let result = run_client_with_socket(client_socket, server_addr, &config).await;
+    assert!(matches!(result, Err(CommError::MaxDuration { secs: 5 })));
+    server.abort();
+}
+
+/// --retransmit-ms の間隔で再送し続け、--deadline-ms を過ぎたら max_retries より先に諦める
+#[tokio::test(start_paused = true)]
+async fn deadline_gives_up_before_max_retries() {
+    let config = Config {
+        count: 3,
+        window: 1,
+        max_retries: 1000,
+        retransmit: Some(Duration::from_millis(100)),
+        deadline: Some(Duration::from_millis(1050)),
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let codec = config.codec();
+    let server = tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (n, from) = server_socket.recv_from(&mut buf).await.unwrap();
+            let msg = codec.decode(&buf[..n]).unwrap();
+            if msg.kind == MsgKind::Syn {
+                let reply = Message::syn(0, Peer::Server).with_session(msg.session);
+                let data = codec.encode(&reply).unwrap();
+                server_socket.send_to(&data, from).await.unwrap();
+            }
+        }
+    });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let result = run_client_with_socket(client_socket, server_addr, &config).await;
+    let Err(CommError::GaveUp { what, retries }) = result else {
+        panic!("GaveUp になるはず");
+    };
+    assert_eq!(what, "no=1");
+    assert_eq!(retries, 10);
+    server.abort();
+}
+
+/// --dual-stack のサーバには IPv4 のクライアントも届く (IPv6 が使えない環境でも IPv4 で待ち受ける)
+#[tokio::test]
+async fn dual_stack_server_accepts_ipv4_clients() {
+    // 空いているポートを借りてすぐ返す
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = Config {
+        port,
+        dual_stack: true,
+        count: 5,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_config = config.clone();
+    let server = tokio::spawn(async move { run_server(&server_config).await });
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let (log, _) = run_client(server_addr, &config).await.unwrap();
+    assert_eq!(log.total(), 5);
+
+    let (server_log, _) = server.await.unwrap().unwrap();
+    assert_eq!(server_log.unwrap().total(), 5);
+}
+
+/// --fast-count のサーバは Data を Ack で応答しながら数え、セッションの終わり方はいつもどおり
+#[tokio::test]
+async fn fast_count_server_counts_every_no() {
+    let config = Config {
+        count: 50,
+        window: 8,
+        payload_size: 512,
+        once: true,
+        quiet: true,
+        fast_count: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, _) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .unwrap();
+    assert_eq!(client_log.total(), 50);
+
+    let (server_log, _) = server.await.unwrap().unwrap();
+    let server_log = server_log.unwrap();
+    assert_eq!(server_log.total(), 50);
+    assert!(server_log.missing(1).is_empty());
+}
+
+/// from が server のメッセージ (折り返してきた自分の応答) には、サーバは応答しない
+#[tokio::test]
+async fn server_ignores_messages_not_from_client() {
+    let config = Config {
+        timeout: Duration::from_millis(200),
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let mut ping: Message =
+        serde_json::from_str(r#"{"no":0,"retry":0,"from":"server","kind":"ping"}"#).unwrap();
+    assert!(send_once(server_addr, &ping, &config).await.is_err());
+
+    ping.from = Peer::Client;
+    let pong = send_once(server_addr, &ping, &config).await.unwrap();
+    assert_eq!(pong.kind, MsgKind::Pong);
+    server.abort();
+}
+
+/// Stat の問い合わせには、セッションを作らずに今のセッション一覧が返ってくる
+#[tokio::test]
+async fn stat_lists_open_sessions() {
+    let config = Config {
+        timeout: Duration::from_millis(500),
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    assert!(query_stat(server_addr, &config).await.unwrap().is_empty());
+
+    // FIN を送らずに 3 個だけ送って、セッションを開いたままにする
+    let codec = config.codec();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server_addr).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    for no in 1..=3 {
+        let msg = Message::data(no, Peer::Client).with_session(7);
+        socket.send(&codec.encode(&msg).unwrap()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .expect("no reply")
+            .unwrap();
+    }
+
+    let stats = query_stat(server_addr, &config).await.unwrap();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].addr, socket.local_addr().unwrap());
+    assert_eq!(stats[0].session, 7);
+    assert_eq!(stats[0].received, 3);
+    assert_eq!(stats[0].max, 3);
+    assert!(stats[0].age_ms >= stats[0].last_seen_ms);
+    server.abort();
+}
+
+/// サーバが動いていないポートに送ると、タイムアウトを待ち続けずに Unreachable で諦める
+#[tokio::test]
+async fn client_gives_up_when_connection_refused() {
+    let config = Config {
+        timeout: Duration::from_millis(200),
+        quiet: true,
+        ..Config::default()
+    };
+
+    // 一度バインドしてから閉じたポートには、誰も待ち受けていない
+    let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = closed.local_addr().unwrap();
+    drop(closed);
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        run_client_with_socket(client_socket, server_addr, &config),
+    )
+    .await
+    .expect("client kept retrying");
+    assert!(matches!(result, Err(CommError::Unreachable { refused: 3 })));
+}
+
+/// --streams では区間ごとに別のセッションで送り、まとめた受信ログは 1 つの転送と同じになる
+#[tokio::test]
+async fn streams_split_the_range_across_sockets() {
+    let config = Config {
+        count: 31,
+        streams: 4,
+        window: 2,
+        max_retries: 20,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server_config = config.clone();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let (client_log, metrics) = run_client(server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert_eq!(client_log.total(), 31);
+    assert_eq!(client_log.build_ranges_summary(), "1-31");
+    assert_eq!(client_log.duplicates(), 0);
+    // 区間ごとに SYN と FIN を送るので、31 個の Data より 4 * 2 個多い
+    assert!(metrics.sent >= 31 + 4 * 2);
+
+    // すべてのストリームが FIN を送ったので、サーバにセッションは残っていない
+    assert!(query_stat(server_addr, &config).await.unwrap().is_empty());
+    server.abort();
+}
+
+/// --corrupt-ack-rate のサーバがずらした no の応答は、クライアントが想定外として数えて再送する
+#[tokio::test]
+async fn corrupted_acks_are_counted_as_unexpected() {
+    let config = Config {
+        count: 20,
+        max_retries: 50,
+        timeout: Duration::from_millis(50),
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let server_config = Config {
+        corrupt_ack_rate: 0.3,
+        seed: Some(5),
+        ..config.clone()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert!(metrics.unexpected > 0);
+    assert!(metrics.retransmits >= metrics.unexpected);
+    // ずらした no は受信ログに入れないので、1..=20 がそのまま残る
+    assert_eq!(client_log.build_ranges_summary(), "1-20");
+    assert_eq!(client_log.duplicates(), 0);
+
+    let (server_log, _) = tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        server_log.expect("server returned no session log").total(),
+        20
+    );
+}
+
+/// --reply-count 3 のサーバの余分な応答は、クライアントが重複として数えるだけで no は正しく進む
+#[tokio::test]
+async fn repeated_replies_are_counted_as_duplicates() {
+    let config = Config {
+        count: 20,
+        max_retries: 20,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let server_config = Config {
+        reply_count: 3,
+        ..config.clone()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let server =
+        tokio::spawn(async move { run_server_with_socket(server_socket, &server_config).await });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client_log, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert_eq!(client_log.build_ranges_summary(), "1-20");
+    assert_eq!(metrics.unexpected, 0);
+    assert_eq!(metrics.retransmits, 0);
+
+    let (server_log, server_metrics) = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        server_log.expect("server returned no session log").total(),
+        20
+    );
+    // SYN-ACK と FIN-ACK のほかに、Data ごとに 3 回応答している (タイムアウトでの再送があればさらに多い)
+    assert!(server_metrics.sent >= 2 + 3 * 20);
+    // 最後の no の余分な応答は FIN を送った後に届くことがあるので、受信ログに入るとは限らない
+    assert!(client_log.duplicates() >= 2 * 19);
+}
+
+/// message_stream はパースできたメッセージだけを、送信元と一緒に順に返す
+#[tokio::test]
+async fn message_stream_skips_garbage() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut messages = Box::pin(message_stream(socket));
+
+    let codec = Config::default().codec();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sender.send_to(b"not a message", addr).await.unwrap();
+    for no in [1, 2] {
+        let msg = Message::data(no, Peer::Client).with_session(9);
+        sender
+            .send_to(&codec.encode(&msg).unwrap(), addr)
+            .await
+            .unwrap();
+    }
+
+    for no in [1, 2] {
+        let (from, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("no message")
+            .expect("stream ended");
+        assert_eq!(from, sender.local_addr().unwrap());
+        assert_eq!((msg.kind, msg.no, msg.session), (MsgKind::Data, no, 9));
+    }
+}
+
+/// run_server_with_hook の on_message には、パースできたメッセージが受信順にすべて渡される
+/// (再送があると同じ no が何度か渡るので、Data は no の集合だけを見る)
+#[tokio::test]
+async fn hook_sees_every_parsed_message() {
+    let config = Config {
+        count: 5,
+        max_retries: 20,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client_socket.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hook_seen = Arc::clone(&seen);
+    let (server_addr, server) = spawn_server_with_hook(&config, move |addr, msg| {
+        assert_eq!(*addr, client_addr);
+        hook_seen.lock().unwrap().push((msg.kind, msg.no));
+    })
+    .await;
+    run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server did not finish")
+        .unwrap()
+        .unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.first(), Some(&(MsgKind::Syn, 1)));
+    assert_eq!(seen.last(), Some(&(MsgKind::Fin, 0)));
+    let data: BTreeSet<u32> = seen
+        .iter()
+        .filter(|(kind, _)| *kind == MsgKind::Data)
+        .map(|&(_, no)| no)
+        .collect();
+    assert_eq!(data, (1..=5).collect());
+}
+
+/// --file のチャンクが u32::MAX を過ぎて 1 に戻っても、--out には元のファイルの順に書き出される
+#[tokio::test]
+async fn file_transfer_wraps_past_u32_max() {
+    let dir = std::env::temp_dir();
+    let file = dir.join(format!("udp_tool_in_{}", std::process::id()));
+    let out = dir.join(format!("udp_tool_out_{}", std::process::id()));
+    let content: Vec<u8> = (0..=37).collect();
+    std::fs::write(&file, &content).unwrap();
+
+    let config = Config {
+        file: Some(file.clone()),
+        payload_size: 4,
+        start_no: u32::MAX - 2,
+        max_retries: 20,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let server_config = Config {
+        file: None,
+        out: Some(out.clone()),
+        ..config.clone()
+    };
+    let ((_, metrics), (server_log, _)) = run_pair(&server_config, &config).await;
+    assert_eq!(metrics.acked, 10);
+    assert_eq!(
+        server_log.build_ranges_summary(),
+        "4294967293-4294967295, 1-7"
+    );
+
+    let written = std::fs::read(&out).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    std::fs::remove_file(&out).unwrap();
+    assert_eq!(written, content);
+}
+
+/// 別セッションの応答は、no が応答待ちのものと同じでも応答済みにしない
+#[tokio::test]
+async fn echoes_from_another_session_are_not_acks() {
+    let config = Config {
+        count: 3,
+        window: 1,
+        max_retries: 20,
+        quiet: true,
+        ..Config::default()
+    };
+
+    // Data ごとに、前回の実行の残りのような別セッションの応答を先に返してから正しい応答を返す
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let codec = config.codec();
+    let server = tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (n, from) = server_socket.recv_from(&mut buf).await.unwrap();
+            let msg = codec.decode(&buf[..n]).unwrap();
+            let reply = match msg.kind {
+                MsgKind::Syn => Message::syn(0, Peer::Server),
+                MsgKind::Data => {
+                    let stale = Message::data(msg.no, Peer::Server)
+                        .with_payload(msg.payload.clone())
+                        .with_session(msg.session.wrapping_add(1));
+                    let data = codec.encode(&stale).unwrap();
+                    server_socket.send_to(&data, from).await.unwrap();
+                    Message::data(msg.no, Peer::Server).with_payload(msg.payload)
+                }
+                MsgKind::Fin => Message::fin(Peer::Server),
+                _ => continue,
+            }
+            .with_session(msg.session);
+            let data = codec.encode(&reply).unwrap();
+            server_socket.send_to(&data, from).await.unwrap();
+        }
+    });
+
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_, metrics) = run_client_with_socket(client_socket, server_addr, &config)
+        .await
+        .expect("client gave up");
+    assert_eq!(metrics.acked, 3);
+    assert!(metrics.unexpected >= 3, "{}", metrics.unexpected);
+    server.abort();
+}
+
+/// 同じポートで再起動したクライアントが SYN なしで Data を送っても、新しいセッションとして受け付ける
+#[tokio::test]
+async fn restarted_client_on_same_port_gets_a_new_session() {
+    let config = Config {
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let (server_addr, server) = spawn_server(&config).await;
+
+    let codec = config.codec();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server_addr).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    let mut exchange = async |msg: Message| {
+        socket.send(&codec.encode(&msg).unwrap()).await.unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .expect("no reply")
+            .unwrap();
+        codec.decode(&buf[..n]).unwrap()
+    };
+
+    // 前の実行は FIN を送らずに落ちた
+    for no in 1..=3 {
+        exchange(Message::data(no, Peer::Client).with_session(1)).await;
+    }
+    // 再起動したクライアントは同じポートから別のセッションで送る
+    for no in 1..=2 {
+        let reply = exchange(Message::data(no, Peer::Client).with_session(2)).await;
+        assert_eq!(
+            (reply.kind, reply.no, reply.session),
+            (MsgKind::Data, no, 2)
+        );
+    }
+    exchange(Message::fin(Peer::Client).with_session(2)).await;
+
+    let (server_log, _) = server.await.unwrap().unwrap();
+    assert_eq!(server_log.unwrap().build_ranges_summary(), "1-2");
+}
+
+/// --state-file の前回の進捗は、クライアントのアドレスごとにそのクライアントのセッションが引き継ぐ
+#[tokio::test]
+async fn state_file_resumes_each_client_separately() {
+    let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let path = std::env::temp_dir().join(format!("udp_tool_resume_{}", std::process::id()));
+    std::fs::write(&path, format!(r#"{{"{a_addr}":[1,2],"{b_addr}":[5]}}"#)).unwrap();
+    let config = Config {
+        state_file: Some(path.clone()),
+        timeout: Duration::from_millis(500),
+        quiet: true,
+        ..Config::default()
+    };
+    let (server_addr, server) = spawn_server(&config).await;
+
+    // 先に届いた b が a の進捗を引き継がないように、b から送る
+    let codec = config.codec();
+    let mut buf = vec![0u8; 2048];
+    for (socket, no, session) in [(&b, 1, 2), (&a, 3, 1)] {
+        let msg = Message::data(no, Peer::Client).with_session(session);
+        socket
+            .send_to(&codec.encode(&msg).unwrap(), server_addr)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .expect("no reply")
+            .unwrap();
+    }
+
+    let stats = query_stat(server_addr, &config).await.unwrap();
+    let received = |addr| {
+        let stat = stats.iter().find(|s| s.addr == addr).unwrap();
+        (stat.received, stat.max)
+    };
+    assert_eq!(received(a_addr), (3, 3));
+    assert_eq!(received(b_addr), (2, 5));
+
+    // 定期的な保存でも、アドレスごとに分けて書き出す
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    let saved: std::collections::BTreeMap<SocketAddr, Vec<u32>> =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved[&a_addr], [1, 2, 3]);
+    assert_eq!(saved[&b_addr], [1, 5]);
+    server.abort();
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// --strict-order は u32::MAX の次を 1 とみなし、1 を飛ばした no で止まる
+#[tokio::test]
+async fn strict_order_follows_the_wrap() {
+    let config = Config {
+        strict_order: true,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let (server_addr, server) = spawn_server(&config).await;
+
+    let codec = config.codec();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server_addr).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    let syn = Message::syn(u32::MAX - 1, Peer::Client).with_session(1);
+    for msg in std::iter::once(syn)
+        .chain([u32::MAX - 1, u32::MAX].map(|no| Message::data(no, Peer::Client).with_session(1)))
+    {
+        socket.send(&codec.encode(&msg).unwrap()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .expect("no reply")
+            .unwrap();
+    }
+    let skipped = Message::data(2, Peer::Client).with_session(1);
+    socket.send(&codec.encode(&skipped).unwrap()).await.unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server did not stop")
+        .unwrap();
+    assert!(matches!(
+        result,
+        Err(CommError::OutOfOrder { no: 2, expected: 1 })
+    ));
+}
+
+/// --pps 50 で SYN と Data 20 個と FIN の 22 個を送ると、最初の 1 個を除いて 20ms ずつ待つので約 0.42 秒かかる
+#[tokio::test]
+async fn pps_paces_every_packet() {
+    let config = Config {
+        count: 20,
+        window: 1,
+        pps: Some(50),
+        max_retries: 20,
+        once: true,
+        quiet: true,
+        ..Config::default()
+    };
+    let started = std::time::Instant::now();
+    let ((_, metrics), (server_log, _)) = run_pair(&config, &config).await;
+    let elapsed = started.elapsed();
+    assert_eq!(server_log.total(), 20);
+    assert!(
+        elapsed >= Duration::from_millis(20) * (metrics.sent as u32 - 1),
+        "{elapsed:?}"
+    );
+    assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    // 送信の間隔を待った時間は RTT に含めない (window 1 なら、待っている間に届いて読まれない応答もない)
+    let rtt = metrics.rtt_avg().unwrap();
+    assert!(rtt < Duration::from_millis(10), "{rtt:?}");
+}